@@ -9,7 +9,7 @@ use actix_web::{
     middleware, web,
 };
 use ecalli_layout_backend::{
-    DB, KEY,
+    DB, KEY, PROJECT_DB, PROJECT_KEY,
     api::{self, StatusResponse},
 };
 use fjall::{Database, KeyspaceCreateOptions};
@@ -42,6 +42,12 @@ fn create_server_app() -> App<
             header::ACCEPT,
             HeaderName::from_static("x-user-agent"),
         ])
+        .expose_headers(vec![
+            HeaderName::from_static(api::SUBSTITUTIONS_HEADER),
+            HeaderName::from_static(api::MAPPINGS_HEADER),
+            HeaderName::from_static(api::FRAME_COUNT_HEADER),
+            HeaderName::from_static(api::ENCODING_HEADER),
+        ])
         .supports_credentials();
 
     App::new()
@@ -50,7 +56,15 @@ fn create_server_app() -> App<
         .service(
             web::scope("/api/v1")
                 .service(health_check)
-                .service(api::handle_poem_animation_generation),
+                .service(api::handle_poem_animation_generation)
+                .service(api::handle_poem_frames_generation)
+                .service(api::handle_poem_static_layout)
+                .service(api::handle_couplet_layout)
+                .service(api::handle_couplet_animation_generation)
+                .service(api::handle_glyph_check)
+                .service(api::handle_static_download)
+                .service(api::handle_project_import)
+                .service(api::handle_project_export),
         )
 }
 
@@ -64,6 +78,17 @@ async fn main() -> io::Result<()> {
     });
     db.keyspace(KEY, KeyspaceCreateOptions::default)
         .expect("Failed to create the default keyspace!");
+    // Projects outlive the server, unlike the progress of its tasks.
+    let project_db = PROJECT_DB.get_or_init(|| {
+        let path = dotenv::var("PROJECT_STORAGE")
+            .unwrap_or_else(|_| "/opt/tku-website/project_storage".to_string());
+        Database::builder(path)
+            .open()
+            .expect("Failed to open the project storage!")
+    });
+    project_db
+        .keyspace(PROJECT_KEY, KeyspaceCreateOptions::default)
+        .expect("Failed to create the project keyspace!");
     HttpServer::new(create_server_app)
         .bind(("127.0.0.1", 18081))?
         .run()
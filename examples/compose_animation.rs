@@ -1,3 +1,6 @@
+// The imports serve the walkthrough below, commented out until it is runnable again.
+#![allow(unused_imports)]
+
 use ecalli_layout_backend::feature::json::{AnimateSubject, AnimationRequest};
 use ecalli_layout_backend::feature::*;
 use std::fs;
@@ -1,13 +1,21 @@
 use crate::{
-    DB, KEY,
+    DB, KEY, PROJECT_DB, PROJECT_KEY,
     feature::{
-        json::{AnimationRequest, CheckStatus},
+        json::{
+            AnimationRequest, CharMapping, CheckStatus, CoupletAnimationRequest, CoupletRequest,
+            CoupletResponse, DownloadRequest, GlyphCheckRequest, GlyphCheckResponse, LayoutRequest,
+            LayoutResponse, ProjectData, ProjectImportRequest, ProjectResponse, Substitution,
+        },
+        project::{export_project, import_project},
         *,
     },
 };
-use actix_web::{HttpResponse, Responder, http::header, post, web};
+use actix_web::{HttpResponse, HttpResponseBuilder, Responder, get, http::header, post, rt, web};
 use fjall::KeyspaceCreateOptions;
+use futures::stream;
 use serde::Serialize;
+use std::io::{self, BufWriter, Write};
+use tokio::sync::mpsc;
 
 #[derive(Debug, Serialize)]
 pub struct StatusResponse {
@@ -15,6 +23,90 @@ pub struct StatusResponse {
     pub message: String,
 }
 
+/// Header listing the words drawn in a fallback font, as JSON.
+pub const SUBSTITUTIONS_HEADER: &str = "x-font-substitutions";
+/// Header listing the words looked up under their stored form, as JSON.
+pub const MAPPINGS_HEADER: &str = "x-char-mappings";
+/// Header giving the number of frames in the animation.
+pub const FRAME_COUNT_HEADER: &str = "x-frame-count";
+/// Header giving the encoder settings chosen to meet the target size, as JSON.
+pub const ENCODING_HEADER: &str = "x-encoding-choice";
+
+// Serialize into JSON fit for a header value, escaping every non-ASCII character.
+fn header_json<T: Serialize>(value: &T) -> Option<String> {
+    let json = serde_json::to_string(value).ok()?;
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                escaped.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    Some(escaped)
+}
+
+// Report the fallback fonts and mapped characters, when there are any.
+fn append_glyph_headers(
+    response: &mut HttpResponseBuilder,
+    substitutions: &[Substitution],
+    mappings: &[CharMapping],
+) {
+    if !substitutions.is_empty()
+        && let Some(substitutions) = header_json(&substitutions)
+    {
+        response.append_header((SUBSTITUTIONS_HEADER, substitutions));
+    }
+    if !mappings.is_empty()
+        && let Some(mappings) = header_json(&mappings)
+    {
+        response.append_header((MAPPINGS_HEADER, mappings));
+    }
+}
+
+// Reply with the animation file, reporting the fallback fonts, mapped
+// characters, frame count and encoder settings in headers.
+fn animation_response(rendered: RenderedAnimation) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(rendered.format.content_type())
+        .append_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"result.{}\"",
+                rendered.format.extension()
+            ),
+        ))
+        .append_header((FRAME_COUNT_HEADER, rendered.frame_count));
+    append_glyph_headers(&mut response, &rendered.substitutions, &rendered.mappings);
+    if let Some(encoding) = rendered.encoding.as_ref().and_then(header_json) {
+        response.append_header((ENCODING_HEADER, encoding));
+    }
+
+    response.body(rendered.data)
+}
+
+// Render and encode the animation on a blocking thread, the worker serving
+// other connections meanwhile.
+async fn encode_animation(pending: PendingAnimation) -> HttpResponse {
+    match rt::task::spawn_blocking(move || pending.encode()).await {
+        Ok(Ok(rendered)) => {
+            // Success: Provide a filename for the animation.
+            animation_response(rendered)
+        }
+        Ok(Err(e)) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
 #[post("/generate-animation")]
 pub async fn handle_poem_animation_generation(body: web::Json<AnimationRequest>) -> impl Responder {
     // Restrict the canvas size to below 4096x4096.
@@ -31,23 +123,267 @@ pub async fn handle_poem_animation_generation(body: web::Json<AnimationRequest>)
         .unwrap()
         .keyspace(KEY, KeyspaceCreateOptions::default)
     {
-        Ok(tree) => match generate_poem_animation_webp(body.into_inner(), &tree).await {
-            Ok(webp_data) => {
-                // Success: Provide a filename for WebP Image.
+        Ok(tree) => match prepare_poem_animation(body.into_inner(), &tree).await {
+            Ok(pending) => encode_animation(pending).await,
+            Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+                code: "200".to_string(),
+                message: format!("Internal error: {e}"),
+            }),
+        },
+
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+// Chunks of a streamed response waiting to be sent.
+const STREAM_CHUNKS: usize = 16;
+// Size of the chunks written to a streamed response.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Writer handing its bytes to a streamed response, blocking while the client is behind.
+struct ChannelWriter(mpsc::Sender<io::Result<web::Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(web::Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The client went away."))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[post("/generate-frames")]
+pub async fn handle_poem_frames_generation(body: web::Json<AnimationRequest>) -> impl Responder {
+    // Restrict the canvas size to below 4096x4096.
+    if body.width > 4096 || body.height > 4096 {
+        return HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: "Canvas dimensions too large.".to_string(),
+        });
+    }
+
+    match DB
+        .get()
+        .unwrap()
+        .keyspace(KEY, KeyspaceCreateOptions::default)
+    {
+        Ok(tree) => match prepare_poem_frames(body.into_inner(), &tree).await {
+            Ok(frames) => {
+                // Draw the frames on a blocking thread, streaming the zip as it is written.
+                let (sender, receiver) = mpsc::channel(STREAM_CHUNKS);
+                rt::task::spawn_blocking(move || {
+                    let writer =
+                        BufWriter::with_capacity(STREAM_CHUNK_SIZE, ChannelWriter(sender.clone()));
+                    if let Err(e) = frames.write_zip(writer) {
+                        // Abort the response, the zip being incomplete.
+                        let _ = sender.blocking_send(Err(io::Error::other(e.to_string())));
+                    }
+                });
+                let body = stream::unfold(receiver, |mut receiver| async move {
+                    let chunk = receiver.recv().await?;
+                    Some((chunk, receiver))
+                });
+
                 HttpResponse::Ok()
-                    .content_type("image/webp")
+                    .content_type("application/zip")
                     .append_header((
                         header::CONTENT_DISPOSITION,
-                        "attachment; filename=\"result.webp\"",
+                        "attachment; filename=\"frames.zip\"",
                     ))
-                    .body(webp_data.to_vec())
+                    .streaming(body)
             }
             Err(e) => HttpResponse::BadRequest().json(StatusResponse {
                 code: "200".to_string(),
                 message: format!("Internal error: {e}"),
             }),
         },
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[post("/layout")]
+pub async fn handle_poem_static_layout(body: web::Json<LayoutRequest>) -> impl Responder {
+    // Restrict the canvas size to below 4096x4096.
+    if body.width > 4096 || body.height > 4096 {
+        return HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: "Canvas dimensions too large.".to_string(),
+        });
+    }
+
+    match compose_poem_static_layout(body.into_inner()).await {
+        Ok(data) => HttpResponse::Ok().json(LayoutResponse {
+            code: "000".to_string(),
+            message: "Layout computed.".to_string(),
+            data,
+        }),
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[post("/layout/couplet")]
+pub async fn handle_couplet_layout(body: web::Json<CoupletRequest>) -> impl Responder {
+    // Restrict the canvas size to below 4096x4096.
+    if body.width > 4096 || body.height > 4096 {
+        return HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: "Canvas dimensions too large.".to_string(),
+        });
+    }
+
+    match compose_couplet_layout(&body).await {
+        Ok(data) => HttpResponse::Ok().json(CoupletResponse {
+            code: "000".to_string(),
+            message: "Layout computed.".to_string(),
+            data,
+        }),
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[post("/generate-couplet-animation")]
+pub async fn handle_couplet_animation_generation(
+    body: web::Json<CoupletAnimationRequest>,
+) -> impl Responder {
+    // Restrict the canvas size to below 4096x4096.
+    if body.couplet.width > 4096 || body.couplet.height > 4096 {
+        return HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: "Canvas dimensions too large.".to_string(),
+        });
+    }
+
+    match DB
+        .get()
+        .unwrap()
+        .keyspace(KEY, KeyspaceCreateOptions::default)
+    {
+        Ok(tree) => match prepare_couplet_animation(body.into_inner(), &tree).await {
+            Ok(pending) => encode_animation(pending).await,
+            Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+                code: "200".to_string(),
+                message: format!("Internal error: {e}"),
+            }),
+        },
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
 
+#[post("/glyphs/check")]
+pub async fn handle_glyph_check(body: web::Json<GlyphCheckRequest>) -> impl Responder {
+    match check_glyph_coverage(&body).await {
+        Ok(data) => HttpResponse::Ok().json(GlyphCheckResponse {
+            code: "000".to_string(),
+            message: "Coverage checked.".to_string(),
+            data,
+        }),
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[post("/download")]
+pub async fn handle_static_download(body: web::Json<DownloadRequest>) -> impl Responder {
+    // Restrict the canvas size to below 4096x4096.
+    if body.width > 4096 || body.height > 4096 {
+        return HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: "Canvas dimensions too large.".to_string(),
+        });
+    }
+
+    match compose_static_image(body.into_inner()).await {
+        Ok(rendered) => {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(rendered.format.content_type())
+                .append_header((
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"result.{}\"",
+                        rendered.format.extension()
+                    ),
+                ));
+            append_glyph_headers(&mut response, &rendered.substitutions, &rendered.mappings);
+            response.body(rendered.data)
+        }
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[post("/projects/import")]
+pub async fn handle_project_import(body: web::Json<ProjectImportRequest>) -> impl Responder {
+    let ProjectImportRequest {
+        id,
+        overwrite,
+        project,
+    } = body.into_inner();
+    match PROJECT_DB
+        .get()
+        .unwrap()
+        .keyspace(PROJECT_KEY, KeyspaceCreateOptions::default)
+    {
+        Ok(tree) => match import_project(&tree, id, overwrite, project) {
+            Ok((id, project)) => HttpResponse::Ok().json(ProjectResponse {
+                code: "000".to_string(),
+                message: "Project imported.".to_string(),
+                data: ProjectData { id, project },
+            }),
+            Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+                code: "200".to_string(),
+                message: format!("Internal error: {e}"),
+            }),
+        },
+        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+            code: "200".to_string(),
+            message: format!("Internal error: {e}"),
+        }),
+    }
+}
+
+#[get("/projects/{id}")]
+pub async fn handle_project_export(path: web::Path<String>) -> impl Responder {
+    let id = path.into_inner();
+    match PROJECT_DB
+        .get()
+        .unwrap()
+        .keyspace(PROJECT_KEY, KeyspaceCreateOptions::default)
+    {
+        Ok(tree) => match export_project(&tree, &id) {
+            Ok(project) => HttpResponse::Ok().json(ProjectResponse {
+                code: "000".to_string(),
+                message: "Project exported.".to_string(),
+                data: ProjectData { id, project },
+            }),
+            Err(e) => HttpResponse::BadRequest().json(StatusResponse {
+                code: "200".to_string(),
+                message: format!("Internal error: {e}"),
+            }),
+        },
         Err(e) => HttpResponse::BadRequest().json(StatusResponse {
             code: "200".to_string(),
             message: format!("Internal error: {e}"),
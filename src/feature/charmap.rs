@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// Simplified and variant (異體字) forms, mapped to the traditional form stored
+/// in the glyph library.
+static STORED_FORMS: LazyLock<HashMap<char, char>> = LazyLock::new(|| {
+    include_str!("charmap.txt")
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(' '))
+        .flat_map(|(stored, forms)| {
+            let stored = stored.chars().next();
+            forms.chars().filter_map(move |form| Some((form, stored?)))
+        })
+        .collect()
+});
+
+/// The form of the character stored in the glyph library.
+pub fn stored_form(word: char) -> char {
+    STORED_FORMS.get(&word).copied().unwrap_or(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simplified_and_variant_forms_map_to_the_stored_one() {
+        assert_eq!(stored_form('来'), '來');
+        assert_eq!(stored_form('値'), '值');
+        assert_eq!(stored_form('來'), '來');
+        assert_eq!(stored_form('永'), '永');
+    }
+
+    #[test]
+    fn ambiguous_simplified_forms_are_left_out() {
+        for word in ['发', '钟', '签'] {
+            assert_eq!(stored_form(word), word);
+        }
+    }
+}
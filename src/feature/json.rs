@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LayoutRequest {
+    /// Newlines start a new column, an empty line or '¶' separates stanzas.
     pub content: String,
     pub font_type: String,
     pub fixed_space: String,
@@ -11,10 +12,148 @@ pub struct LayoutRequest {
     pub height: isize,
     pub letter_space: isize,
     pub line_space: isize,
+    /// Column width of each glyph, fitted to the canvas when omitted.
+    #[serde(default)]
+    pub word_size: Option<isize>,
+    /// Ignore `word_size` and pick the column count filling the canvas best.
+    #[serde(default)]
+    pub auto_fit: bool,
+    /// One of "vertical-rl" (default), "vertical-lr" or "horizontal-tb".
+    #[serde(default)]
+    pub writing_mode: Option<String>,
+    /// Either "drop" (default) or "render" the punctuation marks of the content.
+    #[serde(default)]
+    pub punctuation: Option<String>,
+    /// Title and signature placed as an inscription beside the content.
+    #[serde(default)]
+    pub subject: String,
+    /// Font type of the subject, the content one when omitted.
+    #[serde(default)]
+    pub subject_font_type: Option<String>,
+    #[serde(default)]
+    pub inscription: Option<InscriptionOptions>,
+    /// Lay the content out on a folding fan instead, in vertical modes only.
+    #[serde(default)]
+    pub fan: Option<FanOptions>,
+    /// Look the characters up as written, without mapping simplified or variant forms.
+    #[serde(default)]
+    pub exact_chars: bool,
+}
+
+/// Options for placing the inscription (落款) automatically.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InscriptionOptions {
+    /// Size of the inscription glyphs relative to the content, 0.6 by default.
+    #[serde(default)]
+    pub relative_size: Option<f64>,
+    /// Gap in pixels between the content and the inscription, half a word by default.
+    #[serde(default)]
+    pub gap: Option<isize>,
+    /// Either "start" (top) or "end" (bottom, default) of the content.
+    #[serde(default)]
+    pub align: Option<String>,
+}
+
+/// Pauses added to the frame delay of an animation, in milliseconds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimingOptions {
+    /// Pause between two characters of the same line.
+    #[serde(default)]
+    pub char_pause_ms: u16,
+    /// Pause before a character starting a new line, a column in vertical modes.
+    #[serde(default)]
+    pub column_pause_ms: u16,
+    /// Extra time the final frame stays on screen.
+    #[serde(default)]
+    pub final_hold_ms: u16,
+    /// Blank paper shown before the first stroke.
+    #[serde(default)]
+    pub lead_in_ms: u16,
+}
+
+/// Bounds on the frames of an animation, thinning out the frames of each word evenly.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetOptions {
+    /// Most frames in the animation. The final frame of every word is kept, so a
+    /// budget below one frame a word is refused.
+    #[serde(default)]
+    pub max_frames: Option<usize>,
+    /// Longest duration of the animation, pauses included.
+    #[serde(default)]
+    pub max_duration_ms: Option<u32>,
+    /// Writing speed, above 1 drawing a word with fewer frames and below 1 showing
+    /// each frame longer, 1 by default. Held within 0.1 to 10.
+    #[serde(default)]
+    pub speed: Option<f64>,
+}
+
+/// Settings of the WebP encoder, libwebp's defaults when omitted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncodingOptions {
+    /// Between 0 and 100, the image quality for lossy encoding and the
+    /// compression effort for lossless encoding, 75 by default.
+    #[serde(default)]
+    pub quality: Option<f32>,
+    #[serde(default)]
+    pub lossless: bool,
+    /// Between 0 (fast) and 6 (slower, smaller), 4 by default.
+    #[serde(default)]
+    pub method: Option<usize>,
+    /// Most frames between two key frames, 0 disabling them and 1 making
+    /// every frame a key frame. libwebp cannot honour an interval of 2.
+    #[serde(default)]
+    pub key_frame_interval: Option<u32>,
+    /// Largest file size in bytes. Lossy encoding at a lower quality, then
+    /// with fewer frames, is searched when the requested settings exceed it.
+    #[serde(default)]
+    pub target_size: Option<usize>,
+}
+
+/// Settings an animation was encoded with to meet its target size.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncodingChoice {
+    pub quality: f32,
+    pub lossless: bool,
+    pub method: usize,
+    pub frame_count: usize,
+    pub size: usize,
+}
+
+/// Folding fan (扇面) template, a ring sector opening upwards on the canvas.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FanOptions {
+    /// Angle covered by the fan in degrees, up to 180 and 120 by default.
+    #[serde(default)]
+    pub sweep: Option<f64>,
+    /// Radius of the inner edge relative to the outer one, 0.4 by default.
+    #[serde(default)]
+    pub inner_ratio: Option<f64>,
+    /// Length of every other column relative to the long ones, 0.5 by default.
+    #[serde(default)]
+    pub short_ratio: Option<f64>,
+}
+
+/// Ring sector covered by a fan, in canvas pixels.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FanShape {
+    /// Pivot of the fan, below the paper.
+    pub center_x: f64,
+    pub center_y: f64,
+    pub outer_radius: f64,
+    pub inner_radius: f64,
+    /// Angle covered by the fan in degrees, centred on the vertical.
+    pub sweep: f64,
 }
 
 /// Response format for static layout
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LayoutResponse {
     pub code: String,
@@ -22,26 +161,202 @@ pub struct LayoutResponse {
     pub data: LayoutData,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LayoutData {
     pub letter_space: isize,
     pub line_space: isize,
+    /// Number of columns (or rows) used by the layout.
+    pub columns: isize,
+    /// Line thickness over the original size of the largest glyph.
+    pub scale: f64,
+    pub word: Vec<StaticSubject>,
+    /// Position of the inscription, empty without a subject.
+    #[serde(default)]
+    pub subject: Vec<StaticSubject>,
+    /// Outline of the paper when laid out on a fan.
+    #[serde(default)]
+    pub fan: Option<FanShape>,
+    /// Words drawn in a fallback font.
+    #[serde(default)]
+    pub substitutions: Vec<Substitution>,
+    /// Words looked up under their stored form.
+    #[serde(default)]
+    pub mappings: Vec<CharMapping>,
+}
+
+/// A word drawn in a fallback font as its own font has no drawing of it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Substitution {
+    /// List holding the word, e.g. "word", "subject" or "seal".
+    pub list: String,
+    /// Index of the word among the drawn characters of its list.
+    pub position: usize,
+    pub requested_font: String,
+    pub font: String,
+}
+
+/// A simplified or variant character looked up under the traditional form stored.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CharMapping {
+    /// List holding the word, e.g. "word", "subject" or "seal".
+    pub list: String,
+    /// Index of the word among the drawn characters of its list.
+    pub position: usize,
+    pub from: char,
+    pub to: char,
+}
+
+/// Request format for couplet (對聯) layout
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoupletRequest {
+    /// Upper line (上聯), placed on the right.
+    pub upper_line: String,
+    /// Lower line (下聯), placed on the left.
+    pub lower_line: String,
+    /// Horizontal banner (橫批), placed on top.
+    pub banner: String,
+    pub font_type: String,
+    pub fixed_space: String,
+    pub width: isize,
+    pub height: isize,
+    pub letter_space: isize,
+    /// Margin between the pieces and the canvas edges.
+    pub line_space: isize,
+    /// Size of every glyph, fitted to the canvas when omitted.
+    #[serde(default)]
+    pub word_size: Option<isize>,
+    /// Look the characters up as written, without mapping simplified or variant forms.
+    #[serde(default)]
+    pub exact_chars: bool,
+}
+
+/// Response format for couplet layout
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoupletResponse {
+    pub code: String,
+    pub message: String,
+    pub data: CoupletData,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoupletData {
+    pub word_size: isize,
+    pub upper: CoupletPiece,
+    pub lower: CoupletPiece,
+    pub banner: CoupletPiece,
+    /// Words drawn in a fallback font, listed by "upper", "lower" or "banner".
+    pub substitutions: Vec<Substitution>,
+    /// Words looked up under their stored form, listed the same way.
+    pub mappings: Vec<CharMapping>,
+}
+
+/// One piece of a couplet, positioned on the combined canvas.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoupletPiece {
+    /// Area of the piece including its margin, to crop it as a separate scroll.
+    pub pos_x: f64,
+    pub pos_y: f64,
+    pub width: isize,
+    pub height: isize,
     pub word: Vec<StaticSubject>,
 }
 
+/// Request format for animating a couplet on one canvas.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoupletAnimationRequest {
+    pub task_id: String,
+    pub fps: isize,
+    #[serde(default)]
+    pub timing: Option<TimingOptions>,
+    #[serde(default)]
+    pub encoding: Option<EncodingOptions>,
+    #[serde(default)]
+    pub format: Option<String>,
+    #[serde(flatten)]
+    pub couplet: CoupletRequest,
+}
+
+/// Request format for checking the glyph coverage of a content.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlyphCheckRequest {
+    pub content: String,
+    pub font_types: Vec<String>,
+    /// Either "drop" (default) or "render" the punctuation marks, dropped marks are not checked.
+    #[serde(default)]
+    pub punctuation: Option<String>,
+    /// Look the characters up as written, without mapping simplified or variant forms.
+    #[serde(default)]
+    pub exact_chars: bool,
+}
+
+/// Response format for checking the glyph coverage
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlyphCheckResponse {
+    pub code: String,
+    pub message: String,
+    pub data: Vec<GlyphCoverage>,
+}
+
+/// Coverage of one distinct character of the content.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlyphCoverage {
+    pub word: char,
+    pub fonts: Vec<FontCoverage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FontCoverage {
+    pub font_type: String,
+    /// Form the character is looked up as in the storage of this font.
+    pub lookup: char,
+    /// One of "animated" (a frame archive), "static" (a final drawing only) or "missing".
+    pub source: String,
+    /// Fallback font drawing the character when the requested one has none.
+    pub fallback_font: Option<String>,
+}
+
 /// Request format for downloading static letters
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadRequest {
+    /// The n-th drawn character is placed at the n-th position of the list.
     pub subject: String,
     pub subject_font_type: String,
     pub subject_list: Vec<StaticSubject>,
     pub width: isize,
     pub height: isize,
+    /// One of png (default) or jpeg.
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Leave the paper transparent, only for PNG output.
+    #[serde(default)]
+    pub transparent: bool,
+    /// Either "drop" (default) or "render" the punctuation marks.
+    #[serde(default)]
+    pub punctuation: Option<String>,
+    /// Look the characters up as written, without mapping simplified or variant forms.
+    #[serde(default)]
+    pub exact_chars: bool,
+    #[serde(default)]
+    pub seal: Option<SealOptions>,
+    /// Clip the paper to the fan fitting the canvas, leaving the rest transparent.
+    #[serde(default)]
+    pub fan: Option<FanOptions>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StaticSubject {
     pub pos_x: f64,
@@ -49,6 +364,9 @@ pub struct StaticSubject {
     pub width: isize,
     pub height: isize,
     pub line: isize,
+    /// Clockwise rotation in degrees about the centre of the glyph.
+    #[serde(default)]
+    pub rotation: f64,
 }
 
 /// Request format for creating poem animation.
@@ -59,15 +377,87 @@ pub struct AnimationRequest {
     pub subject: String,
     pub subject_font_type: String,
     pub subject_list: Vec<AnimateSubject>,
+    /// Newlines and stanza markers have no layer in the word list.
     pub content: String,
     pub font_type: String,
     pub word_list: Vec<AnimateSubject>,
     pub width: isize,
     pub height: isize,
     pub fps: isize,
+    /// Draw the words in the reading order of this writing mode instead of the list order.
+    #[serde(default)]
+    pub writing_mode: Option<String>,
+    /// Either "drop" (default) or "render" the punctuation marks, dropped marks have no layer.
+    #[serde(default)]
+    pub punctuation: Option<String>,
+    /// Place the subject beside the word list, replacing the subject list.
+    #[serde(default)]
+    pub inscription: Option<InscriptionOptions>,
+    #[serde(default)]
+    pub seal: Option<SealOptions>,
+    /// Clip the paper to the fan fitting the canvas, leaving the rest transparent.
+    #[serde(default)]
+    pub fan: Option<FanOptions>,
+    /// Give repeated words different variants chosen from this seed, unless
+    /// their layer picks one.
+    #[serde(default)]
+    pub variant_seed: Option<u64>,
+    /// Look the characters up as written, without mapping simplified or variant forms.
+    #[serde(default)]
+    pub exact_chars: bool,
+    #[serde(default)]
+    pub timing: Option<TimingOptions>,
+    /// One of "sequential" (default), "subject-first", "columns", "simultaneous",
+    /// "reverse" or "explicit".
+    #[serde(default)]
+    pub order: Option<String>,
+    /// Layers drawn one after another by the explicit order, numbered through
+    /// the word list then the subject list. Layers left out are not drawn.
+    #[serde(default)]
+    pub sequence: Option<Vec<usize>>,
+    #[serde(default)]
+    pub budget: Option<BudgetOptions>,
+    /// Only for WebP output.
+    #[serde(default)]
+    pub encoding: Option<EncodingOptions>,
+    /// One of "webp" (default), "gif" or "apng".
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Seal (印章) stamped onto the piece, either uploaded or carved from seal script glyphs.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SealOptions {
+    /// Base64 encoded PNG of an uploaded seal, used instead of the text.
+    #[serde(default)]
+    pub image: Option<String>,
+    /// One to four characters carved in seal script.
+    #[serde(default)]
+    pub text: String,
+    /// Either "square" (default) or "round".
+    #[serde(default)]
+    pub shape: Option<String>,
+    /// Side length of the seal in pixels.
+    pub size: isize,
+    /// One of "bottom-left" (default), "bottom-right", "top-left", "top-right"
+    /// or "inscription" to follow the last subject word.
+    #[serde(default)]
+    pub anchor: Option<String>,
+    /// Distance in pixels to the canvas edge or the inscription, a quarter of the size by default.
+    #[serde(default)]
+    pub margin: Option<isize>,
+    /// Explicit top-left position, overriding the anchor.
+    #[serde(default)]
+    pub pos_x: Option<f64>,
+    #[serde(default)]
+    pub pos_y: Option<f64>,
+    /// Stamp the seal on a final frame instead of the blank paper.
+    #[serde(default)]
+    pub animate: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AnimateSubject {
     pub pos_x: f64,
@@ -75,6 +465,123 @@ pub struct AnimateSubject {
     pub width: isize,
     pub height: isize,
     pub modify_x: f64,
+    /// Clockwise rotation in degrees about the centre of the glyph.
+    #[serde(default)]
+    pub rotation: f64,
+    /// Font type of this word, the one of its list when omitted.
+    #[serde(default)]
+    pub font_type: Option<String>,
+    /// Variant of the drawing, stored as `{char}.N.zip`, 0 being the default `{char}.zip`.
+    #[serde(default)]
+    pub variant: Option<u32>,
+}
+
+impl From<StaticSubject> for AnimateSubject {
+    fn from(subject: StaticSubject) -> Self {
+        AnimateSubject {
+            pos_x: subject.pos_x,
+            pos_y: subject.pos_y,
+            width: subject.width,
+            height: subject.height,
+            modify_x: 0.,
+            rotation: subject.rotation,
+            font_type: None,
+            variant: None,
+        }
+    }
+}
+
+/// Content of `timing.json` in a zip of animation frames.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameSequenceTiming {
+    pub frame_count: usize,
+    pub duration_ms: i32,
+    pub frames: Vec<FrameTiming>,
+}
+
+/// How long a frame of the zip is shown.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameTiming {
+    pub file: String,
+    pub duration_ms: i32,
+}
+
+/// A finished piece saved as a project, in the latest schema version.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Project {
+    pub version: u32,
+    #[serde(default)]
+    pub name: String,
+    pub content: String,
+    pub font_type: String,
+    #[serde(default)]
+    pub subject: String,
+    pub subject_font_type: String,
+    pub width: isize,
+    pub height: isize,
+    pub fps: isize,
+    pub word_list: Vec<AnimateSubject>,
+    #[serde(default)]
+    pub subject_list: Vec<AnimateSubject>,
+    #[serde(default)]
+    pub writing_mode: Option<String>,
+    #[serde(default)]
+    pub punctuation: Option<String>,
+    #[serde(default)]
+    pub inscription: Option<InscriptionOptions>,
+    #[serde(default)]
+    pub seal: Option<SealOptions>,
+    #[serde(default)]
+    pub fan: Option<FanOptions>,
+    #[serde(default)]
+    pub variant_seed: Option<u64>,
+    #[serde(default)]
+    pub exact_chars: bool,
+    #[serde(default)]
+    pub timing: Option<TimingOptions>,
+    #[serde(default)]
+    pub order: Option<String>,
+    #[serde(default)]
+    pub sequence: Option<Vec<usize>>,
+    #[serde(default)]
+    pub budget: Option<BudgetOptions>,
+    #[serde(default)]
+    pub encoding: Option<EncodingOptions>,
+    /// One of "webp", "gif" or "apng".
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// Request format for importing a project document of any schema version.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectImportRequest {
+    /// Identifier to store the project under, a new one when omitted.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Replace the project already stored under the id instead of refusing it.
+    #[serde(default)]
+    pub overwrite: bool,
+    pub project: serde_json::Value,
+}
+
+/// Response format for imported and exported projects
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectResponse {
+    pub code: String,
+    pub message: String,
+    pub data: ProjectData,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectData {
+    pub id: String,
+    pub project: Project,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,929 @@
+use std::str::FromStr;
+
+use super::AppError;
+use super::json::{FanShape, StaticSubject};
+
+/// Direction the lines of a piece are written in.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritingMode {
+    /// Traditional columns, read from the right-most one.
+    #[default]
+    VerticalRtl,
+    /// Columns read from the left-most one.
+    VerticalLtr,
+    /// Rows read from top to bottom, left to right, as on signboards and banners.
+    HorizontalLtr,
+}
+
+impl WritingMode {
+    pub fn is_vertical(&self) -> bool {
+        !matches!(self, WritingMode::HorizontalLtr)
+    }
+}
+
+impl FromStr for WritingMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vertical-rl" => Ok(WritingMode::VerticalRtl),
+            "vertical-lr" => Ok(WritingMode::VerticalLtr),
+            "horizontal-tb" => Ok(WritingMode::HorizontalLtr),
+            _ => Err(AppError::InvalidWritingMode(s.to_string())),
+        }
+    }
+}
+
+/// Length of a punctuation cell along its line, relative to the line thickness.
+const PUNCTUATION_CELL: f64 = 0.5;
+/// Largest side of a punctuation mark, relative to the line thickness.
+const PUNCTUATION_MARK: f64 = 0.35;
+
+/// A cell of a line as seen by the layout engine.
+///
+/// Glyph sizes are the original (width, height) of the drawing, where (0, 0)
+/// marks a missing drawing that still takes up its cell.
+#[derive(Clone, Copy)]
+pub enum LayoutItem {
+    Glyph((u32, u32)),
+    /// A small mark set in the corner of a short cell.
+    Punctuation((u32, u32)),
+    /// A short blank cell left by a dropped punctuation mark.
+    Pause,
+    /// Ends the current line, two in a row leave a blank line.
+    LineBreak,
+}
+
+/// Layout parameters in canvas pixels.
+///
+/// A line is a column in vertical modes and a row in horizontal mode.
+#[derive(Clone, Copy)]
+pub struct LayoutConfig {
+    pub canvas_width: f64,
+    pub canvas_height: f64,
+    /// Line thickness every glyph is normalised to.
+    pub word_size: f64,
+    /// Gap between two glyphs of the same line.
+    pub letter_space: f64,
+    /// Gap between two lines.
+    pub line_space: f64,
+    /// Give every glyph a square cell instead of its own aspect ratio.
+    pub fixed_space: bool,
+    pub writing_mode: WritingMode,
+}
+
+impl LayoutConfig {
+    // Canvas length available along a line.
+    fn line_extent(&self) -> f64 {
+        if self.writing_mode.is_vertical() {
+            self.canvas_height
+        } else {
+            self.canvas_width
+        }
+    }
+
+    // Canvas length available to stack the lines.
+    fn block_extent(&self) -> f64 {
+        if self.writing_mode.is_vertical() {
+            self.canvas_width
+        } else {
+            self.canvas_height
+        }
+    }
+
+    // Length of the cell along its line relative to the line thickness,
+    // `None` for a line break.
+    fn cell_ratio(&self, item: LayoutItem) -> Option<f64> {
+        match item {
+            LayoutItem::LineBreak => None,
+            LayoutItem::Punctuation(_) | LayoutItem::Pause => Some(PUNCTUATION_CELL),
+            LayoutItem::Glyph((width, height)) => {
+                if self.fixed_space || width == 0 || height == 0 {
+                    Some(1.)
+                } else if self.writing_mode.is_vertical() {
+                    Some(height as f64 / width as f64)
+                } else {
+                    Some(width as f64 / height as f64)
+                }
+            }
+        }
+    }
+
+    // Length taken by the cell along its line, `None` for a line break.
+    fn cell_length(&self, item: LayoutItem) -> Option<f64> {
+        self.cell_ratio(item).map(|ratio| self.word_size * ratio)
+    }
+
+    // Size of the drawing inside its cell.
+    fn glyph_size(&self, item: LayoutItem) -> (f64, f64) {
+        let (bound, (width, height)) = match item {
+            LayoutItem::Glyph(size) => (self.word_size, size),
+            LayoutItem::Punctuation(size) => (self.word_size * PUNCTUATION_MARK, size),
+            LayoutItem::Pause | LayoutItem::LineBreak => (0., (0, 0)),
+        };
+
+        if width == 0 || height == 0 {
+            (bound, bound)
+        } else if self.fixed_space || matches!(item, LayoutItem::Punctuation(_)) {
+            let scale = (bound / width as f64).min(bound / height as f64);
+            (width as f64 * scale, height as f64 * scale)
+        } else if self.writing_mode.is_vertical() {
+            (bound, self.cell_length(item).unwrap_or(bound))
+        } else {
+            (self.cell_length(item).unwrap_or(bound), bound)
+        }
+    }
+}
+
+// Sequentially assigns each cell to a line, starting a new line whenever
+// the next cell would overflow the `max_length` of its line index or follows
+// a line break (`None`).
+//
+// Returns: (line index of each cell, length of each line)
+fn split_lines(
+    cells: &[Option<f64>],
+    letter_space: f64,
+    max_length: impl Fn(usize) -> f64,
+) -> (Vec<usize>, Vec<f64>) {
+    let mut line_map = Vec::with_capacity(cells.len());
+    let mut line_lengths: Vec<f64> = Vec::new();
+    // Whether the last line still accepts cells.
+    let mut line_open = false;
+
+    for &cell in cells {
+        let line = line_lengths.len().saturating_sub(1);
+        match (cell, line_lengths.last_mut()) {
+            (None, _) => {
+                // A break right after another one leaves a blank line.
+                if !line_open {
+                    line_lengths.push(0.);
+                }
+                line_open = false;
+            }
+            (Some(len), Some(line_length))
+                if line_open && *line_length + letter_space + len <= max_length(line) =>
+            {
+                *line_length += letter_space + len;
+            }
+            (Some(len), _) => {
+                line_lengths.push(len);
+                line_open = true;
+            }
+        }
+        line_map.push(line_lengths.len().saturating_sub(1));
+    }
+
+    (line_map, line_lengths)
+}
+
+/// Places the items line by line following the writing mode, centred on the canvas.
+///
+/// Returns the position of every drawn item in reading order, pauses excluded.
+pub fn layout_glyphs(items: &[LayoutItem], config: &LayoutConfig) -> Vec<StaticSubject> {
+    let cells: Vec<Option<f64>> = items.iter().map(|&item| config.cell_length(item)).collect();
+    let (line_map, line_lengths) =
+        split_lines(&cells, config.letter_space, |_| config.line_extent());
+
+    let line_count = line_lengths.len() as f64;
+    let block_thickness =
+        line_count * config.word_size + (line_count - 1.).max(0.) * config.line_space;
+    let block_length = line_lengths.iter().copied().fold(0., f64::max);
+    let offset_line = ((config.line_extent() - block_length) / 2.).max(0.);
+    let offset_block = ((config.block_extent() - block_thickness) / 2.).max(0.);
+
+    let mut line_offsets = vec![0.; line_lengths.len()];
+    items
+        .iter()
+        .zip(cells.iter())
+        .zip(line_map.iter())
+        .filter_map(|((&item, &cell), &line)| {
+            let cell = cell?;
+            let line_pos = offset_line + line_offsets[line];
+            line_offsets[line] += cell + config.letter_space;
+            let stacked = line as f64 * (config.word_size + config.line_space);
+
+            let (cell_x, cell_y, cell_w, cell_h) = match config.writing_mode {
+                // The first column sits on the right edge of the block.
+                WritingMode::VerticalRtl => (
+                    offset_block + block_thickness - config.word_size - stacked,
+                    line_pos,
+                    config.word_size,
+                    cell,
+                ),
+                WritingMode::VerticalLtr => {
+                    (offset_block + stacked, line_pos, config.word_size, cell)
+                }
+                WritingMode::HorizontalLtr => {
+                    (line_pos, offset_block + stacked, cell, config.word_size)
+                }
+            };
+
+            place_item(item, (cell_x, cell_y, cell_w, cell_h), line, config)
+        })
+        .collect()
+}
+
+// Position the drawing of the item inside its cell given as (x, y, width, height).
+fn place_item(
+    item: LayoutItem,
+    (cell_x, cell_y, cell_w, cell_h): (f64, f64, f64, f64),
+    line: usize,
+    config: &LayoutConfig,
+) -> Option<StaticSubject> {
+    let (width, height) = config.glyph_size(item);
+    let (pos_x, pos_y) = match item {
+        LayoutItem::Pause | LayoutItem::LineBreak => return None,
+        // Marks sit in the top-right corner of a column, bottom-left of a row.
+        LayoutItem::Punctuation(_) if config.writing_mode.is_vertical() => {
+            (cell_x + cell_w - width, cell_y)
+        }
+        LayoutItem::Punctuation(_) => (cell_x, cell_y + cell_h - height),
+        LayoutItem::Glyph(_) => (
+            cell_x + (cell_w - width) / 2.,
+            cell_y + (cell_h - height) / 2.,
+        ),
+    };
+
+    Some(StaticSubject {
+        pos_x: pos_x.round(),
+        pos_y: pos_y.round(),
+        width: width.round() as isize,
+        height: height.round() as isize,
+        line: line as isize,
+        rotation: 0.,
+    })
+}
+
+/// Bounding box (left, top, right, bottom) of boxes given as (x, y, width, height).
+pub fn bounds(boxes: &[(f64, f64, f64, f64)]) -> Option<(f64, f64, f64, f64)> {
+    boxes.iter().fold(None, |acc, &(x, y, w, h)| {
+        let (left, top, right, bottom) = acc.unwrap_or((x, y, x + w, y + h));
+        Some((left.min(x), top.min(y), right.max(x + w), bottom.max(y + h)))
+    })
+}
+
+/// Moves the placed glyphs so that their bounding box is centred on the canvas.
+pub fn center_on_canvas(placed: &mut [StaticSubject], canvas_width: f64, canvas_height: f64) {
+    let boxes: Vec<(f64, f64, f64, f64)> = placed
+        .iter()
+        .map(|s| (s.pos_x, s.pos_y, s.width as f64, s.height as f64))
+        .collect();
+    if let Some((left, top, right, bottom)) = bounds(&boxes) {
+        let dx = ((canvas_width - (right - left)) / 2. - left).round();
+        let dy = ((canvas_height - (bottom - top)) / 2. - top).round();
+        translate(placed, dx, dy);
+    }
+}
+
+pub fn translate(placed: &mut [StaticSubject], dx: f64, dy: f64) {
+    for subject in placed.iter_mut() {
+        subject.pos_x += dx;
+        subject.pos_y += dy;
+    }
+}
+
+/// Where the inscription sits along its line, relative to the body text.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum InscriptionAlign {
+    /// Aligned to the top of the body, or its left in horizontal mode.
+    Start,
+    /// Aligned to the bottom of the body, or its right in horizontal mode.
+    #[default]
+    End,
+}
+
+impl FromStr for InscriptionAlign {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(InscriptionAlign::Start),
+            "end" => Ok(InscriptionAlign::End),
+            _ => Err(AppError::InvalidLayout(format!(
+                "unknown inscription alignment {s}"
+            ))),
+        }
+    }
+}
+
+/// Places the inscription in lines following the body text, which is given by
+/// its bounding box: to the left of right-to-left columns, to the right of
+/// left-to-right columns and below rows.
+///
+/// `config` carries the already scaled word size and spacing of the inscription.
+pub fn layout_inscription(
+    items: &[LayoutItem],
+    body: (f64, f64, f64, f64),
+    gap: f64,
+    align: InscriptionAlign,
+    config: &LayoutConfig,
+) -> Vec<StaticSubject> {
+    let (left, top, right, bottom) = body;
+    let (line_start, line_end) = if config.writing_mode.is_vertical() {
+        (top, bottom)
+    } else {
+        (left, right)
+    };
+
+    // Break into several lines only when the inscription outgrows the body.
+    let cells: Vec<Option<f64>> = items.iter().map(|&item| config.cell_length(item)).collect();
+    let longest_cell = cells.iter().flatten().copied().fold(0., f64::max);
+    let max_length = if line_end - line_start >= longest_cell {
+        line_end - line_start
+    } else {
+        config.line_extent()
+    };
+    let (line_map, line_lengths) = split_lines(&cells, config.letter_space, |_| max_length);
+
+    let mut line_offsets: Vec<f64> = line_lengths
+        .iter()
+        .map(|length| match align {
+            InscriptionAlign::Start => line_start,
+            InscriptionAlign::End => line_end - length,
+        })
+        .collect();
+    items
+        .iter()
+        .zip(cells.iter())
+        .zip(line_map.iter())
+        .filter_map(|((&item, &cell), &line)| {
+            let cell = cell?;
+            let line_pos = line_offsets[line];
+            line_offsets[line] += cell + config.letter_space;
+            let stacked = gap + line as f64 * (config.word_size + config.line_space);
+
+            let rect = match config.writing_mode {
+                WritingMode::VerticalRtl => (
+                    left - stacked - config.word_size,
+                    line_pos,
+                    config.word_size,
+                    cell,
+                ),
+                WritingMode::VerticalLtr => (right + stacked, line_pos, config.word_size, cell),
+                WritingMode::HorizontalLtr => (line_pos, bottom + stacked, cell, config.word_size),
+            };
+            place_item(item, rect, line, config)
+        })
+        .collect()
+}
+
+// Number of lines needed to hold the cells at the given line thickness.
+fn count_lines(
+    ratios: &[Option<f64>],
+    word_size: f64,
+    letter_space: f64,
+    max_length: f64,
+) -> usize {
+    let cells: Vec<Option<f64>> = ratios.iter().map(|r| r.map(|r| r * word_size)).collect();
+    if cells.iter().flatten().any(|&len| len > max_length) {
+        return usize::MAX;
+    }
+    split_lines(&cells, letter_space, |_| max_length).1.len()
+}
+
+/// Searches the line count K from 1 up to N for the largest line thickness at
+/// which the glyphs still fit in the canvas.
+pub fn fit_word_size(items: &[LayoutItem], config: &LayoutConfig) -> f64 {
+    let ratios: Vec<Option<f64>> = items.iter().map(|&item| config.cell_ratio(item)).collect();
+    let (line_extent, block_extent) = (config.line_extent(), config.block_extent());
+    let mut max_word_size = 0.;
+
+    for k in 1..=items.len().max(1) {
+        // The thickest line fitting K lines side by side.
+        let thickness_bound = (block_extent - (k - 1) as f64 * config.line_space) / k as f64;
+        if thickness_bound <= max_word_size {
+            // Bounds only shrink as K grows.
+            break;
+        }
+
+        // Binary search the thickest line still fitting in at most K lines.
+        let (mut low, mut high) = (0., thickness_bound);
+        if count_lines(&ratios, high, config.letter_space, line_extent) <= k {
+            low = high;
+        } else {
+            for _ in 0..48 {
+                let mid = (low + high) / 2.;
+                if count_lines(&ratios, mid, config.letter_space, line_extent) <= k {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+        }
+
+        max_word_size = f64::max(max_word_size, low);
+    }
+
+    max_word_size.floor().max(1.)
+}
+
+/// Sorts the indices of the placed glyphs, given as (x, y, width, height), in
+/// the order a reader following the writing mode meets them.
+pub fn reading_order(boxes: &[(f64, f64, f64, f64)], mode: WritingMode) -> Vec<usize> {
+    reading_lines(boxes, mode).into_iter().flatten().collect()
+}
+
+/// Groups glyph boxes into the lines of the writing mode, each in reading
+/// order, the lines themselves in reading order.
+pub fn reading_lines(boxes: &[(f64, f64, f64, f64)], mode: WritingMode) -> Vec<Vec<usize>> {
+    let centres: Vec<(f64, f64, f64)> = boxes.iter().map(|&b| line_centre(b, mode)).collect();
+
+    let mut indices: Vec<usize> = (0..boxes.len()).collect();
+    indices.sort_by(|&a, &b| centres[a].0.total_cmp(&centres[b].0));
+
+    // Group glyphs on the same line as the line's first one.
+    let mut lines: Vec<Vec<usize>> = Vec::new();
+    for idx in indices {
+        match lines.last_mut() {
+            Some(line) if same_line(boxes[line[0]], boxes[idx], mode) => line.push(idx),
+            _ => lines.push(vec![idx]),
+        }
+    }
+    if mode == WritingMode::VerticalRtl {
+        lines.reverse();
+    }
+
+    for line in &mut lines {
+        line.sort_by(|&a, &b| centres[a].1.total_cmp(&centres[b].1));
+    }
+    lines
+}
+
+// Centre of a glyph across and along its line, and its thickness.
+fn line_centre((x, y, w, h): (f64, f64, f64, f64), mode: WritingMode) -> (f64, f64, f64) {
+    if mode.is_vertical() {
+        (x + w / 2., y + h / 2., w)
+    } else {
+        (y + h / 2., x + w / 2., h)
+    }
+}
+
+/// Whether the glyph box `other` sits on the line of `first`, its centre
+/// being within half a glyph of the first one across the line.
+pub fn same_line(
+    first: (f64, f64, f64, f64),
+    other: (f64, f64, f64, f64),
+    mode: WritingMode,
+) -> bool {
+    let (first, _, thickness) = line_centre(first, mode);
+    let (other, _, _) = line_centre(other, mode);
+    (other - first).abs() <= thickness / 2.
+}
+
+/// A couplet (對聯) laid out on one canvas: the banner (橫批) row on top, the
+/// upper line on the right and the lower line on the left, all sharing one
+/// glyph size.
+pub struct CoupletPlacement {
+    pub word_size: f64,
+    pub upper: Vec<StaticSubject>,
+    pub lower: Vec<StaticSubject>,
+    pub banner: Vec<StaticSubject>,
+}
+
+// Length of a single line made of the given cell ratios.
+fn single_line_length(ratios: &[f64], word_size: f64, letter_space: f64) -> f64 {
+    let count = ratios.len() as f64;
+    ratios.iter().sum::<f64>() * word_size + (count - 1.).max(0.) * letter_space
+}
+
+/// Places the three lines of a couplet, keeping `line_space` as the margin
+/// between the pieces and the canvas edges.
+///
+/// Line breaks are ignored, each piece is a single line. When `fit_to_canvas`
+/// is set, the largest word size fitting every piece replaces the configured one.
+pub fn layout_couplet(
+    upper: &[LayoutItem],
+    lower: &[LayoutItem],
+    banner: &[LayoutItem],
+    config: &LayoutConfig,
+    fit_to_canvas: bool,
+) -> CoupletPlacement {
+    let column = LayoutConfig {
+        writing_mode: WritingMode::VerticalRtl,
+        ..*config
+    };
+    let row = LayoutConfig {
+        writing_mode: WritingMode::HorizontalLtr,
+        ..*config
+    };
+    let single_line = |items: &[LayoutItem]| -> Vec<LayoutItem> {
+        items
+            .iter()
+            .copied()
+            .filter(|item| !matches!(item, LayoutItem::LineBreak))
+            .collect()
+    };
+    let (upper, lower, banner) = (single_line(upper), single_line(lower), single_line(banner));
+    let ratios = |items: &[LayoutItem], config: &LayoutConfig| -> Vec<f64> {
+        items
+            .iter()
+            .filter_map(|&item| config.cell_ratio(item))
+            .collect()
+    };
+    let (upper_ratios, lower_ratios, banner_ratios) = (
+        ratios(&upper, &column),
+        ratios(&lower, &column),
+        ratios(&banner, &row),
+    );
+
+    let (width, height) = (config.canvas_width, config.canvas_height);
+    let margin = config.line_space;
+    let letter_space = config.letter_space;
+    let fits = |word_size: f64| {
+        let column_extent = height - 3. * margin - word_size;
+        single_line_length(&upper_ratios, word_size, letter_space) <= column_extent
+            && single_line_length(&lower_ratios, word_size, letter_space) <= column_extent
+            && single_line_length(&banner_ratios, word_size, letter_space) <= width - 2. * margin
+            && 2. * word_size + 3. * margin <= width
+    };
+
+    let word_size = if fit_to_canvas {
+        // Binary search the largest word size fitting every piece.
+        let (mut low, mut high) = (0., width.min(height));
+        for _ in 0..48 {
+            let mid = (low + high) / 2.;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        f64::floor(low).max(1.)
+    } else {
+        config.word_size
+    };
+
+    // Lay each piece out on a canvas of its own size, then move it into place.
+    let piece = |items: &[LayoutItem], ratios: &[f64], config: LayoutConfig| {
+        let length = single_line_length(ratios, word_size, letter_space) + 1.;
+        let (canvas_width, canvas_height) = if config.writing_mode.is_vertical() {
+            (word_size, length)
+        } else {
+            (length, word_size)
+        };
+        let placed = layout_glyphs(
+            items,
+            &LayoutConfig {
+                canvas_width,
+                canvas_height,
+                word_size,
+                ..config
+            },
+        );
+        (placed, length)
+    };
+    let (mut upper_placed, upper_length) = piece(&upper, &upper_ratios, column);
+    let (mut lower_placed, lower_length) = piece(&lower, &lower_ratios, column);
+    let (mut banner_placed, banner_length) = piece(&banner, &banner_ratios, row);
+
+    // Both lines start at the same height, below the banner.
+    let column_top = 2. * margin + word_size;
+    let column_extent = height - 3. * margin - word_size;
+    let top = column_top + ((column_extent - upper_length.max(lower_length)) / 2.).max(0.);
+    translate(
+        &mut upper_placed,
+        (width - margin - word_size).round(),
+        top.round(),
+    );
+    translate(&mut lower_placed, margin.round(), top.round());
+    translate(
+        &mut banner_placed,
+        ((width - banner_length) / 2.).round(),
+        margin.round(),
+    );
+
+    CoupletPlacement {
+        word_size,
+        upper: upper_placed,
+        lower: lower_placed,
+        banner: banner_placed,
+    }
+}
+
+/// The largest fan with the given sweep in degrees, up to 180, and inner
+/// radius ratio fitting the canvas, centred on it.
+pub fn fit_fan(canvas_width: f64, canvas_height: f64, sweep: f64, inner_ratio: f64) -> FanShape {
+    let half_sweep = sweep.to_radians() / 2.;
+    // The outer corners are the widest points, the inner corners the lowest.
+    let outer_radius = f64::min(
+        canvas_width / (2. * half_sweep.sin()),
+        canvas_height / (1. - inner_ratio * half_sweep.cos()),
+    );
+    let inner_radius = outer_radius * inner_ratio;
+    let fan_height = outer_radius - inner_radius * half_sweep.cos();
+
+    FanShape {
+        center_x: canvas_width / 2.,
+        center_y: (canvas_height - fan_height) / 2. + outer_radius,
+        outer_radius,
+        inner_radius,
+        sweep,
+    }
+}
+
+/// Whether the canvas point lies on the fan.
+pub fn fan_contains(fan: &FanShape, x: f64, y: f64) -> bool {
+    let (dx, dy) = (x - fan.center_x, fan.center_y - y);
+    let radius = dx.hypot(dy);
+    // Angle from the vertical, positive to the right.
+    let angle = dx.atan2(dy).to_degrees();
+    radius >= fan.inner_radius && radius <= fan.outer_radius && angle.abs() <= fan.sweep / 2.
+}
+
+/// Places the items in columns radiating from the pivot of the fan, long and
+/// short columns alternating from its outer edge, each glyph turned to the
+/// angle of its column.
+///
+/// `letter_space` also keeps the columns off the edges of the fan. When
+/// `fit_to_canvas` is set, the largest word size fitting the fan replaces the
+/// configured one.
+///
+/// Returns: (word size, position of every drawn item in reading order)
+pub fn layout_fan(
+    items: &[LayoutItem],
+    config: &LayoutConfig,
+    fan: &FanShape,
+    short_ratio: f64,
+    fit_to_canvas: bool,
+) -> (f64, Vec<StaticSubject>) {
+    let column = LayoutConfig {
+        writing_mode: WritingMode::VerticalRtl,
+        ..*config
+    };
+    let ratios: Vec<Option<f64>> = items.iter().map(|&item| column.cell_ratio(item)).collect();
+    let sweep = fan.sweep.to_radians();
+    let start = fan.outer_radius - config.letter_space;
+    let long_length = fan.outer_radius - fan.inner_radius - 2. * config.letter_space;
+    let max_length = |line: usize| {
+        if line.is_multiple_of(2) {
+            long_length
+        } else {
+            long_length * short_ratio
+        }
+    };
+    // Angle between two columns, keeping `line_space` between the innermost glyphs.
+    let column_angle = |word_size: f64| {
+        let innermost = fan.inner_radius + config.letter_space + word_size / 2.;
+        (word_size + config.line_space) / innermost
+    };
+    let cells_at = |word_size: f64| -> Vec<Option<f64>> {
+        ratios.iter().map(|r| r.map(|r| r * word_size)).collect()
+    };
+
+    let fits = |word_size: f64| {
+        let step = column_angle(word_size);
+        let glyph_angle = step * word_size / (word_size + config.line_space);
+        let (_, line_lengths) = split_lines(&cells_at(word_size), config.letter_space, max_length);
+        let columns = line_lengths.len() as f64;
+        line_lengths
+            .iter()
+            .enumerate()
+            .all(|(line, &length)| length <= max_length(line))
+            && (columns - 1.).max(0.) * step + glyph_angle <= sweep
+    };
+    let word_size = if fit_to_canvas {
+        // Binary search the largest word size fitting the fan.
+        let (mut low, mut high) = (0., long_length.max(0.));
+        for _ in 0..48 {
+            let mid = (low + high) / 2.;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        f64::floor(low).max(1.)
+    } else {
+        config.word_size
+    };
+
+    let column = LayoutConfig {
+        word_size,
+        ..column
+    };
+    let cells = cells_at(word_size);
+    let (line_map, line_lengths) = split_lines(&cells, config.letter_space, max_length);
+    let step = column_angle(word_size);
+    let first_angle = (line_lengths.len() as f64 - 1.).max(0.) * step / 2.;
+
+    let mut line_offsets = vec![0.; line_lengths.len()];
+    let placed = items
+        .iter()
+        .zip(cells.iter())
+        .zip(line_map.iter())
+        .filter_map(|((&item, &cell), &line)| {
+            let cell = cell?;
+            let line_pos = line_offsets[line];
+            line_offsets[line] += cell + config.letter_space;
+
+            // Place the item in an upright column running inwards from the outer edge.
+            let rect = (-word_size / 2., line_pos, word_size, cell);
+            let mut placed = place_item(item, rect, line, &column)?;
+            let (width, height) = (placed.width as f64, placed.height as f64);
+            let across = placed.pos_x + width / 2.;
+            let radius = start - (placed.pos_y + height / 2.);
+
+            // Then turn the column about the pivot, the first one on the right.
+            let angle = match config.writing_mode {
+                WritingMode::VerticalLtr => line as f64 * step - first_angle,
+                _ => first_angle - line as f64 * step,
+            };
+            let (sin, cos) = angle.sin_cos();
+            let centre_x = fan.center_x + radius * sin + across * cos;
+            let centre_y = fan.center_y - radius * cos + across * sin;
+            placed.pos_x = (centre_x - width / 2.).round();
+            placed.pos_y = (centre_y - height / 2.).round();
+            placed.rotation = angle.to_degrees();
+            Some(placed)
+        })
+        .collect();
+
+    (word_size, placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(canvas_width: f64, canvas_height: f64) -> LayoutConfig {
+        LayoutConfig {
+            canvas_width,
+            canvas_height,
+            word_size: 100.,
+            letter_space: 0.,
+            line_space: 0.,
+            fixed_space: true,
+            writing_mode: WritingMode::default(),
+        }
+    }
+
+    #[test]
+    fn split_lines_wraps_on_overflow() {
+        let cells = [Some(40.), Some(40.), Some(40.)];
+        let (line_map, line_lengths) = split_lines(&cells, 10., |_| 100.);
+        assert_eq!(line_map, [0, 0, 1]);
+        assert_eq!(line_lengths, [90., 40.]);
+    }
+
+    #[test]
+    fn split_lines_keeps_an_oversized_cell_on_its_own_line() {
+        let (line_map, line_lengths) = split_lines(&[Some(150.), Some(10.)], 0., |_| 100.);
+        assert_eq!(line_map, [0, 1]);
+        assert_eq!(line_lengths, [150., 10.]);
+    }
+
+    #[test]
+    fn fit_word_size_fills_a_single_column() {
+        let items = [LayoutItem::Glyph((10, 10)); 4];
+        // Four square glyphs in one 400 pixel column as wide as the canvas.
+        assert_eq!(fit_word_size(&items, &config(100., 400.)), 100.);
+        // With room across, one glyph per column is larger.
+        assert_eq!(fit_word_size(&items, &config(1000., 400.)), 250.);
+    }
+
+    #[test]
+    fn fit_word_size_balances_columns() {
+        let items = [LayoutItem::Glyph((10, 10)); 4];
+        // Two columns of two glyphs beat one column of four or four of one.
+        assert_eq!(fit_word_size(&items, &config(200., 200.)), 100.);
+    }
+
+    #[test]
+    fn fit_word_size_keeps_every_glyph_inside_the_canvas() {
+        let items = [LayoutItem::Glyph((30, 50)); 7];
+        let mut config = LayoutConfig {
+            letter_space: 6.,
+            line_space: 12.,
+            fixed_space: false,
+            ..config(300., 500.)
+        };
+        config.word_size = fit_word_size(&items, &config);
+        for glyph in layout_glyphs(&items, &config) {
+            assert!(glyph.pos_x >= 0. && glyph.pos_y >= 0.);
+            assert!(glyph.pos_x + glyph.width as f64 <= 300.);
+            assert!(glyph.pos_y + glyph.height as f64 <= 500.);
+        }
+    }
+
+    #[test]
+    fn layout_glyphs_starts_on_the_right_column() {
+        let items = [LayoutItem::Glyph((10, 10)); 4];
+        let placed = layout_glyphs(&items, &config(200., 200.));
+        let positions: Vec<(f64, f64)> = placed.iter().map(|g| (g.pos_x, g.pos_y)).collect();
+        assert_eq!(positions, [(100., 0.), (100., 100.), (0., 0.), (0., 100.)]);
+    }
+
+    #[test]
+    fn reading_order_follows_the_writing_mode() {
+        // Two columns of two glyphs, then the same glyphs as two rows.
+        let columns = [
+            (0., 0., 10., 10.),
+            (0., 10., 10., 10.),
+            (20., 10., 10., 10.),
+            (20., 0., 10., 10.),
+        ];
+        assert_eq!(
+            reading_order(&columns, WritingMode::VerticalRtl),
+            [3, 2, 0, 1]
+        );
+        assert_eq!(
+            reading_order(&columns, WritingMode::VerticalLtr),
+            [0, 1, 3, 2]
+        );
+        assert_eq!(
+            reading_order(&columns, WritingMode::HorizontalLtr),
+            [0, 3, 1, 2]
+        );
+    }
+
+    #[test]
+    fn horizontal_layout_fills_rows_from_the_top() {
+        let items = [LayoutItem::Glyph((10, 10)); 3];
+        let config = LayoutConfig {
+            writing_mode: WritingMode::HorizontalLtr,
+            ..config(200., 200.)
+        };
+        let placed = layout_glyphs(&items, &config);
+        let positions: Vec<(f64, f64)> = placed.iter().map(|g| (g.pos_x, g.pos_y)).collect();
+        assert_eq!(positions, [(0., 0.), (100., 0.), (0., 100.)]);
+    }
+
+    #[test]
+    fn inscription_sits_left_of_the_body_aligned_to_its_end() {
+        let items = [LayoutItem::Glyph((10, 10)); 2];
+        let config = LayoutConfig {
+            word_size: 20.,
+            ..config(400., 400.)
+        };
+        let body = (100., 0., 200., 300.);
+        let placed = layout_inscription(&items, body, 10., InscriptionAlign::End, &config);
+        let positions: Vec<(f64, f64)> = placed.iter().map(|g| (g.pos_x, g.pos_y)).collect();
+        assert_eq!(positions, [(70., 260.), (70., 280.)]);
+
+        let placed = layout_inscription(&items, body, 10., InscriptionAlign::Start, &config);
+        assert_eq!(placed[0].pos_y, 0.);
+    }
+
+    #[test]
+    fn inscription_wraps_only_past_the_body() {
+        let items = [LayoutItem::Glyph((10, 10)); 4];
+        let config = LayoutConfig {
+            word_size: 20.,
+            ..config(400., 400.)
+        };
+        let placed = layout_inscription(
+            &items,
+            (100., 0., 200., 50.),
+            0.,
+            InscriptionAlign::Start,
+            &config,
+        );
+        let lines: Vec<isize> = placed.iter().map(|g| g.line).collect();
+        assert_eq!(lines, [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn split_lines_leaves_a_blank_line_between_two_breaks() {
+        let cells = [Some(10.), None, Some(10.), None, None, Some(10.)];
+        let (line_map, line_lengths) = split_lines(&cells, 0., |_| 100.);
+        assert_eq!(line_map, [0, 0, 1, 1, 2, 3]);
+        assert_eq!(line_lengths, [10., 10., 0., 10.]);
+    }
+
+    #[test]
+    fn couplet_puts_the_upper_line_right_and_the_banner_on_top() {
+        let line = [LayoutItem::Glyph((10, 10)); 7];
+        let banner = [LayoutItem::Glyph((10, 10)); 4];
+        let placement = layout_couplet(&line, &line, &banner, &config(600., 800.), true);
+        assert_eq!(placement.upper.len(), 7);
+        assert_eq!(placement.banner.len(), 4);
+
+        let lower_right = placement
+            .lower
+            .iter()
+            .map(|g| g.pos_x + g.width as f64)
+            .fold(0., f64::max);
+        assert!(placement.upper.iter().all(|g| g.pos_x >= lower_right));
+        let banner_bottom = placement
+            .banner
+            .iter()
+            .map(|g| g.pos_y + g.height as f64)
+            .fold(0., f64::max);
+        assert!(
+            placement
+                .upper
+                .iter()
+                .chain(&placement.lower)
+                .all(|g| g.pos_y >= banner_bottom)
+        );
+        for g in placement
+            .upper
+            .iter()
+            .chain(&placement.lower)
+            .chain(&placement.banner)
+        {
+            assert!(g.pos_x >= 0. && g.pos_x + g.width as f64 <= 600.);
+            assert!(g.pos_y >= 0. && g.pos_y + g.height as f64 <= 800.);
+        }
+    }
+}
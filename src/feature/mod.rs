@@ -1,23 +1,36 @@
+pub mod charmap;
 pub mod json;
+pub mod layout;
+pub mod output;
+pub mod project;
+pub mod seal;
+pub mod text;
 use json::*;
+use layout::{InscriptionAlign, LayoutConfig, LayoutItem, WritingMode};
+use output::{AnimationFormat, ApngWriter, FrameZipWriter, GifWriter, StillFormat, clear_paper};
+use seal::Seal;
+use text::{PunctuationMode, Token};
 
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::Hash;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Write};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
 
 use azure_storage::prelude::*;
 use azure_storage_blobs::prelude::*;
 use fjall::Keyspace;
 use image::{
-    ExtendedColorType, ImageEncoder, Rgba, RgbaImage,
-    codecs::png::PngEncoder,
+    Rgba, RgbaImage,
     imageops::{self, FilterType},
 };
-use webp_animation::{Encoder, WebPData};
-use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+use quick_cache::sync::Cache;
+use webp_animation::{
+    Encoder, EncoderOptions, EncodingConfig, EncodingType, LossyEncodingConfig, WebPData,
+};
+use zip::ZipArchive;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
@@ -31,6 +44,10 @@ pub enum AppError {
     ZipFailure(#[from] zip::result::ZipError),
     #[error(transparent)]
     WebpFailure(#[from] webp_animation::Error),
+    #[error(transparent)]
+    GifFailure(#[from] gif::EncodingError),
+    #[error(transparent)]
+    PngFailure(#[from] png::EncodingError),
     #[error("Invalid file name extracted from zip archive: {0}")]
     InvalidFileName(String),
     #[error(transparent)]
@@ -45,6 +62,30 @@ pub enum AppError {
     IoError(#[from] std::io::Error),
     #[error("Invalid subject font type: {0}")]
     InvalidFontType(String),
+    #[error("Invalid layout parameters: {0}")]
+    InvalidLayout(String),
+    #[error("Invalid writing mode: {0}")]
+    InvalidWritingMode(String),
+    #[error("Invalid punctuation mode: {0}")]
+    InvalidPunctuationMode(String),
+    #[error("Invalid seal: {0}")]
+    InvalidSeal(String),
+    #[error("Invalid animation order: {0}")]
+    InvalidOrder(String),
+    #[error("Invalid frame budget: {0}")]
+    InvalidBudget(String),
+    #[error("Invalid encoding: {0}")]
+    InvalidEncoding(String),
+    #[error("Invalid output format: {0}")]
+    InvalidFormat(String),
+    #[error(transparent)]
+    JsonFailure(#[from] serde_json::Error),
+    #[error("Invalid project: {0}")]
+    InvalidProject(String),
+    #[error("No project stored as {0}")]
+    ProjectNotFound(String),
+    #[error("A project is already stored as {0}")]
+    ProjectExists(String),
 }
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -73,6 +114,37 @@ impl FromStr for CalliFont {
     }
 }
 
+impl CalliFont {
+    pub const ALL: [CalliFont; 6] = [
+        CalliFont::Clerical,
+        CalliFont::Cursive,
+        CalliFont::StandardCursive,
+        CalliFont::Regular,
+        CalliFont::Seal,
+        CalliFont::SemiCursive,
+    ];
+
+    /// Fonts tried in turn, closest style first, when this one has no drawing of a word.
+    pub fn default_fallbacks(&self) -> Vec<CalliFont> {
+        match self {
+            CalliFont::StandardCursive => vec![
+                CalliFont::Cursive,
+                CalliFont::SemiCursive,
+                CalliFont::Regular,
+            ],
+            CalliFont::Cursive => vec![
+                CalliFont::StandardCursive,
+                CalliFont::SemiCursive,
+                CalliFont::Regular,
+            ],
+            CalliFont::SemiCursive => vec![CalliFont::Regular],
+            CalliFont::Regular => vec![CalliFont::SemiCursive],
+            CalliFont::Clerical => vec![CalliFont::Regular],
+            CalliFont::Seal => vec![CalliFont::Clerical, CalliFont::Regular],
+        }
+    }
+}
+
 impl fmt::Display for CalliFont {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
@@ -86,18 +158,86 @@ impl fmt::Display for CalliFont {
     }
 }
 
+/// A drawing in storage: font type, word and variant, 0 being the default one.
+pub type GlyphKey = (CalliFont, char, u32);
+
+/// Most variants looked for beside the default drawing of a word.
+const MAX_VARIANTS: u32 = 8;
+
+/// Area (x, y, width, height) of a drawing in pixels.
+pub type PixelBox = (u32, u32, u32, u32);
+
+/// Drawings whose ink bounding box is kept, the rarely used ones being evicted first.
+const INK_BOUNDS_CAPACITY: usize = 16_384;
+
+/// Ink bounding box of the drawings loaded recently, keyed by storage account,
+/// container and blob name.
+static INK_BOUNDS: LazyLock<Cache<String, Option<PixelBox>>> =
+    LazyLock::new(|| Cache::new(INK_BOUNDS_CAPACITY));
+
+/// Smallest ink coverage, out of 255, counted as part of the drawing.
+const INK_THRESHOLD: u32 = 16;
+
+// Crop every frame of a drawing to the ink of its final frame, so that the
+// transparent margins of the source images do not widen the gaps.
+fn trim_frames(drawing: &str, frames: &mut [WordFrame]) {
+    let ink_bounds = match INK_BOUNDS.get(drawing) {
+        Some(ink_bounds) => ink_bounds,
+        None => {
+            let ink_bounds = frames.last().and_then(WordFrame::ink_bounds);
+            INK_BOUNDS.insert(drawing.to_string(), ink_bounds);
+            ink_bounds
+        }
+    };
+
+    if let Some(ink_bounds) = ink_bounds {
+        for frame in frames.iter_mut() {
+            frame.crop(ink_bounds);
+        }
+    }
+}
+
 pub struct BlobStorageConfig {
     pub account: String,
     pub access_key: String,
     pub container: String,
+    /// Fonts tried in turn when a font has no drawing of a word.
+    pub fallbacks: HashMap<CalliFont, Vec<CalliFont>>,
+    /// Look simplified and variant characters missing as written up under their stored form.
+    pub map_chars: bool,
+    // Form each (font type, word) was settled to be looked up as.
+    forms: Mutex<HashMap<(CalliFont, char), char>>,
 }
 
 impl BlobStorageConfig {
+    /// The fallback chain of each font may be set as a comma separated list of
+    /// font types, e.g. `FONT_FALLBACK_STANDARDCURSIVE=草書,行書,楷書`, an empty
+    /// one disabling the fallback.
     pub fn from_local_env() -> Result<Self, AppError> {
+        let fallbacks = CalliFont::ALL
+            .iter()
+            .map(|font_type| {
+                let env_key = format!("FONT_FALLBACK_{}", font_type.to_string().to_uppercase());
+                let chain = match dotenv::var(env_key) {
+                    Ok(list) => list
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(CalliFont::from_str)
+                        .collect::<Result<Vec<_>, _>>()?,
+                    Err(_) => font_type.default_fallbacks(),
+                };
+                Ok((*font_type, chain))
+            })
+            .collect::<Result<_, AppError>>()?;
+
         Ok(BlobStorageConfig {
             account: dotenv::var("STORAGE_ACCOUNT")?,
             access_key: dotenv::var("STORAGE_ACCESS_KEY")?,
             container: dotenv::var("STORAGE_CONTAINER")?,
+            fallbacks,
+            map_chars: true,
+            forms: Mutex::default(),
         })
     }
 
@@ -105,6 +245,48 @@ impl BlobStorageConfig {
         self.container = name.to_string();
     }
 
+    pub fn set_char_mapping(&mut self, enabled: bool) {
+        self.map_chars = enabled;
+    }
+
+    /// The character the word is looked up as in storage: as written when the
+    /// font has a drawing of it, otherwise its stored form. Many simplified
+    /// forms are also traditional characters of their own, e.g. 叶 or 适.
+    pub async fn lookup_form(&self, font_type: &CalliFont, word: char) -> Result<char, AppError> {
+        let stored = charmap::stored_form(word);
+        if !self.map_chars || stored == word {
+            return Ok(word);
+        }
+        let key = (*font_type, word);
+        if let Some(form) = self
+            .forms
+            .lock()
+            .ok()
+            .and_then(|forms| forms.get(&key).copied())
+        {
+            return Ok(form);
+        }
+
+        let form = if self.has_drawing(font_type, word).await? {
+            word
+        } else {
+            stored
+        };
+        if let Ok(mut forms) = self.forms.lock() {
+            forms.insert(key, form);
+        }
+        Ok(form)
+    }
+
+    // Whether the font has a drawing of the word as written, animated or not.
+    async fn has_drawing(&self, font_type: &CalliFont, word: char) -> Result<bool, AppError> {
+        Ok(self.get_frame_client(font_type, word, 0).exists().await?
+            || self
+                .get_static_font_client(font_type, word)
+                .exists()
+                .await?)
+    }
+
     pub fn get_static_font_client(&self, font_type: &CalliFont, font_name: char) -> BlobClient {
         let blob_name = format!("{font_type}/{font_name}.png");
         let storage_credit =
@@ -116,9 +298,17 @@ impl BlobStorageConfig {
             .blob_client(blob_name)
     }
 
-    pub fn get_frame_client(&self, font_type: &CalliFont, zip_name: char) -> BlobClient {
+    pub fn get_frame_client(
+        &self,
+        font_type: &CalliFont,
+        zip_name: char,
+        variant: u32,
+    ) -> BlobClient {
         //let primary_endpoint = format!("https://{}.blob.core.windows.net/", self.account);
-        let blob_name = format!("{font_type}/{zip_name}.zip");
+        let blob_name = match variant {
+            0 => format!("{font_type}/{zip_name}.zip"),
+            _ => format!("{font_type}/{zip_name}.{variant}.zip"),
+        };
         let storage_credit =
             StorageCredentials::access_key(self.account.clone(), self.access_key.clone());
         let service_client = BlobServiceClient::new(&self.account, storage_credit);
@@ -128,304 +318,2182 @@ impl BlobStorageConfig {
             .blob_client(blob_name)
     }
 
+    // Punctuation marks are shared by every font type.
+    pub fn get_punctuation_client(&self, mark: char) -> BlobClient {
+        let blob_name = format!("Punctuation/{mark}.png");
+        let storage_credit =
+            StorageCredentials::access_key(self.account.clone(), self.access_key.clone());
+        let service_client = BlobServiceClient::new(&self.account, storage_credit);
+
+        service_client
+            .container_client(self.container.clone())
+            .blob_client(blob_name)
+    }
+
+    // Name of a drawing across storages, the same blob name being found in
+    // other accounts and containers.
+    fn drawing_key(&self, blob_name: &str) -> String {
+        format!("{}/{}/{blob_name}", self.account, self.container)
+    }
+
+    async fn get_punctuation_frame(&self, mark: char) -> Result<WordFrame, AppError> {
+        let blob_client = self.get_punctuation_client(mark);
+        if blob_client.exists().await? {
+            let blob_name = blob_client.blob_name().to_string();
+            let mut mark_frame = WordFrame::load_static_from_client(blob_client).await?;
+            trim_frames(
+                &self.drawing_key(&blob_name),
+                std::slice::from_mut(&mut mark_frame),
+            );
+            Ok(mark_frame)
+        } else {
+            Ok(WordFrame::empty(mark))
+        }
+    }
+
+    // Number of drawings of the word, the default one and its numbered variants.
+    async fn count_variants(&self, font_type: &CalliFont, word: char) -> Result<u32, AppError> {
+        if text::is_punctuation(word) {
+            return Ok(1);
+        }
+        let word = self.lookup_form(font_type, word).await?;
+        let mut count = 1;
+        while count <= MAX_VARIANTS
+            && self
+                .get_frame_client(font_type, word, count)
+                .exists()
+                .await?
+        {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Load every frame of a drawing: the variant archive, the default one, then
+    // the static image. `None` when the font has no drawing of the word.
+    async fn load_word_frames(
+        &self,
+        font_type: &CalliFont,
+        word: char,
+        variant: u32,
+    ) -> Result<Option<Vec<WordFrame>>, AppError> {
+        let word = self.lookup_form(font_type, word).await?;
+        // Download request to BLOB storage.
+        let mut blob_client = self.get_frame_client(font_type, word, variant);
+        if variant > 0 && !blob_client.exists().await? {
+            blob_client = self.get_frame_client(font_type, word, 0);
+        }
+        let (blob_name, mut word_frames) = if blob_client.exists().await? {
+            let blob_name = blob_client.blob_name().to_string();
+            (blob_name, WordFrame::load_from_client(blob_client).await?)
+        } else {
+            let static_blob_client = self.get_static_font_client(font_type, word);
+            if !static_blob_client.exists().await? {
+                return Ok(None);
+            }
+            let blob_name = static_blob_client.blob_name().to_string();
+            let word_frame = WordFrame::load_static_from_client(static_blob_client).await?;
+            (blob_name, vec![word_frame])
+        };
+        trim_frames(&self.drawing_key(&blob_name), &mut word_frames);
+
+        Ok(Some(word_frames))
+    }
+
+    // Load the finished drawing of a word, preferring the static image over the
+    // last frame of its archive as it avoids downloading every stroke.
+    async fn load_word_glyph(
+        &self,
+        font_type: &CalliFont,
+        word: char,
+    ) -> Result<Option<WordFrame>, AppError> {
+        let word = self.lookup_form(font_type, word).await?;
+        let static_blob_client = self.get_static_font_client(font_type, word);
+        if static_blob_client.exists().await? {
+            let blob_name = static_blob_client.blob_name().to_string();
+            let mut word_frame = WordFrame::load_static_from_client(static_blob_client).await?;
+            trim_frames(
+                &self.drawing_key(&blob_name),
+                std::slice::from_mut(&mut word_frame),
+            );
+            return Ok(Some(word_frame));
+        }
+
+        let blob_client = self.get_frame_client(font_type, word, 0);
+        if blob_client.exists().await? {
+            let blob_name = blob_client.blob_name().to_string();
+            let mut word_frames = WordFrame::load_from_client(blob_client).await?;
+            trim_frames(&self.drawing_key(&blob_name), &mut word_frames);
+            Ok(word_frames.pop())
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Where the drawing of a word would be loaded from, without downloading it.
+    async fn glyph_source(
+        &self,
+        font_type: &CalliFont,
+        word: char,
+    ) -> Result<GlyphSource, AppError> {
+        if text::is_punctuation(word) {
+            return Ok(if self.get_punctuation_client(word).exists().await? {
+                GlyphSource::Static
+            } else {
+                GlyphSource::Missing
+            });
+        }
+
+        let word = self.lookup_form(font_type, word).await?;
+        if self.get_frame_client(font_type, word, 0).exists().await? {
+            Ok(GlyphSource::Animated)
+        } else if self
+            .get_static_font_client(font_type, word)
+            .exists()
+            .await?
+        {
+            Ok(GlyphSource::Static)
+        } else {
+            Ok(GlyphSource::Missing)
+        }
+    }
+
+    // Fonts tried in turn when the given one has no drawing of a word.
+    fn fallback_chain(&self, font_type: &CalliFont) -> &[CalliFont] {
+        self.fallbacks.get(font_type).map_or(&[], Vec::as_slice)
+    }
+
+    // Fetch the frames of every (font type, word, variant) key, across fonts in one pass.
+    // A missing variant falls back to the default drawing of the word, and a
+    // missing word to the fallback fonts, variants being specific to a font.
+    //
+    // Returns: (frames of each key, fallback font of the substituted keys)
     async fn get_poem_frames_by_font_type(
+        &self,
+        words: impl IntoIterator<Item = GlyphKey>,
+    ) -> Result<
+        (
+            HashMap<GlyphKey, Vec<WordFrame>>,
+            HashMap<GlyphKey, CalliFont>,
+        ),
+        AppError,
+    > {
+        let mut result = HashMap::new();
+        let mut substituted = HashMap::new();
+        for key in words {
+            let (font_type, word, variant) = key;
+            if result.contains_key(&key) {
+                continue;
+            }
+            if text::is_punctuation(word) {
+                result.insert(key, vec![self.get_punctuation_frame(word).await?]);
+                continue;
+            }
+
+            let mut word_frames = self.load_word_frames(&font_type, word, variant).await?;
+            let mut chain = self.fallback_chain(&font_type).iter();
+            while word_frames.is_none()
+                && let Some(fallback) = chain.next()
+            {
+                word_frames = self.load_word_frames(fallback, word, 0).await?;
+                if word_frames.is_some() {
+                    substituted.insert(key, *fallback);
+                }
+            }
+            result.insert(
+                key,
+                word_frames.unwrap_or_else(|| vec![WordFrame::empty(word)]),
+            );
+        }
+
+        Ok((result, substituted))
+    }
+
+    // Fetch the finished drawing of each word, falling back to the chain of the font.
+    //
+    // Returns: (drawing of each word, fallback font of the substituted words)
+    async fn get_poem_glyphs_by_font_type(
         &self,
         font_type: &CalliFont,
         content: &str,
-    ) -> Result<HashMap<(CalliFont, char), Vec<WordFrame>>, AppError> {
+    ) -> Result<
+        (
+            HashMap<(CalliFont, char), WordFrame>,
+            HashMap<char, CalliFont>,
+        ),
+        AppError,
+    > {
         let mut result = HashMap::with_capacity(content.len());
+        let mut substituted = HashMap::new();
         for word in content.chars() {
-            // Ensure the frontend has already excluded none Chinese letters.
-            if matches!(word, '，' | '。' | '？' | '！' | ',' | '?' | '!') {
-                unreachable!();
+            let key = (*font_type, word);
+            if result.contains_key(&key) {
+                continue;
+            }
+            if text::is_punctuation(word) {
+                result.insert(key, self.get_punctuation_frame(word).await?);
+                continue;
             }
 
-            if !result.contains_key(&(*font_type, word)) {
-                // Download request to BLOB storage.
-                let blob_client = self.get_frame_client(font_type, word);
-                let key = (*font_type, word);
-                // Check if the selected word exists.
-                if blob_client.exists().await? {
-                    let word_frames = WordFrame::load_from_client(blob_client).await?;
-                    result.insert(key, word_frames);
-                } else {
-                    let static_blob_client = self.get_static_font_client(font_type, word);
-                    if static_blob_client.exists().await? {
-                        let word_frame =
-                            WordFrame::load_static_from_client(static_blob_client).await?;
-                        result.insert(key, vec![word_frame]);
-                    } else {
-                        result.insert(
-                            key,
-                            vec![WordFrame {
-                                name: word,
-                                img: RgbaImage::new(0, 0),
-                                width: 0,
-                                height: 0,
-                                pos_x: 0,
-                                pos_y: 0,
-                            }],
-                        );
-                    }
+            let mut word_frame = self.load_word_glyph(font_type, word).await?;
+            let mut chain = self.fallback_chain(font_type).iter();
+            while word_frame.is_none()
+                && let Some(fallback) = chain.next()
+            {
+                word_frame = self.load_word_glyph(fallback, word).await?;
+                if word_frame.is_some() {
+                    substituted.insert(word, *fallback);
                 }
             }
+            result.insert(key, word_frame.unwrap_or_else(|| WordFrame::empty(word)));
         }
 
-        Ok(result)
+        Ok((result, substituted))
     }
 }
 
-/*
-// Greedily distributes words into K columns sequentially (in input order)
-// to minimize the max height (H_max).
+/// Where the drawing of a word is stored.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GlyphSource {
+    /// An archive of stroke frames.
+    Animated,
+    /// A final drawing only.
+    Static,
+    Missing,
+}
+
+impl fmt::Display for GlyphSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            GlyphSource::Animated => write!(f, "animated"),
+            GlyphSource::Static => write!(f, "static"),
+            GlyphSource::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+/// Order in which the words of an animation are written.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnimationOrder {
+    /// The body word by word, then the subject.
+    #[default]
+    Sequential,
+    /// The subject word by word, then the body.
+    SubjectFirst,
+    /// One line after another, the words of a line written at the same time.
+    Columns,
+    /// Every word written at the same time.
+    Simultaneous,
+    /// The sequential order backwards, the subject first.
+    Reverse,
+    /// The layers listed by the request.
+    Explicit,
+}
+
+impl FromStr for AnimationOrder {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(AnimationOrder::Sequential),
+            "subject-first" => Ok(AnimationOrder::SubjectFirst),
+            "columns" => Ok(AnimationOrder::Columns),
+            "simultaneous" => Ok(AnimationOrder::Simultaneous),
+            "reverse" => Ok(AnimationOrder::Reverse),
+            "explicit" => Ok(AnimationOrder::Explicit),
+            _ => Err(AppError::InvalidOrder(s.to_string())),
+        }
+    }
+}
+
+fn parse_punctuation_mode(mode: Option<&str>) -> Result<PunctuationMode, AppError> {
+    mode.map_or(Ok(PunctuationMode::default()), PunctuationMode::from_str)
+}
+
+// Parse the string flags sent by the frontend, e.g. "true" or "1".
+fn parse_flag(flag: &str) -> bool {
+    matches!(
+        flag.trim().to_ascii_lowercase().as_str(),
+        "true" | "1" | "y" | "yes"
+    )
+}
+
+fn substitution(
+    list: &str,
+    position: usize,
+    requested_font: CalliFont,
+    font: CalliFont,
+) -> Substitution {
+    Substitution {
+        list: list.to_string(),
+        position,
+        requested_font: requested_font.to_string(),
+        font: font.to_string(),
+    }
+}
+
+// Words of the `list`, given with their font, looked up under another form than written.
+async fn char_mappings(
+    blob_config: &BlobStorageConfig,
+    list: &str,
+    words: impl IntoIterator<Item = (CalliFont, char)>,
+) -> Result<Vec<CharMapping>, AppError> {
+    let mut mappings = Vec::new();
+    for (position, (font_type, word)) in words.into_iter().enumerate() {
+        let stored = blob_config.lookup_form(&font_type, word).await?;
+        if stored != word {
+            mappings.push(CharMapping {
+                list: list.to_string(),
+                position,
+                from: word,
+                to: stored,
+            });
+        }
+    }
+    Ok(mappings)
+}
+
+// Turn the content into layout items carrying the size of their final drawing.
+//
+// Returns: (layout items, words of the `list` drawn in a fallback font)
+async fn load_layout_items(
+    blob_config: &BlobStorageConfig,
+    font_type: CalliFont,
+    content: &str,
+    punctuation: PunctuationMode,
+    list: &str,
+) -> Result<(Vec<LayoutItem>, Vec<Substitution>), AppError> {
+    let tokens = text::tokenize(content, punctuation);
+    let drawn: String = tokens.iter().filter_map(Token::drawn_char).collect();
+    let (glyphs, substituted) = blob_config
+        .get_poem_glyphs_by_font_type(&font_type, &drawn)
+        .await?;
+    let substitutions = drawn
+        .chars()
+        .enumerate()
+        .filter_map(|(position, word)| {
+            let fallback = substituted.get(&word)?;
+            Some(substitution(list, position, font_type, *fallback))
+        })
+        .collect();
+    let glyph_size = |word: char| {
+        glyphs
+            .get(&(font_type, word))
+            .map_or((0, 0), |frame| (frame.width, frame.height))
+    };
+
+    Ok((layout_items(&tokens, glyph_size), substitutions))
+}
+
+// Pair each token with the size of its drawing.
+fn layout_items(tokens: &[Token], glyph_size: impl Fn(char) -> (u32, u32)) -> Vec<LayoutItem> {
+    tokens
+        .iter()
+        .flat_map(|token| match *token {
+            Token::Word(word) => vec![LayoutItem::Glyph(glyph_size(word))],
+            Token::Punctuation(mark) => vec![LayoutItem::Punctuation(glyph_size(mark))],
+            Token::Pause => vec![LayoutItem::Pause],
+            Token::LineBreak => vec![LayoutItem::LineBreak],
+            Token::StanzaBreak => vec![LayoutItem::LineBreak, LayoutItem::LineBreak],
+        })
+        .collect()
+}
+
+// Fit the fan described by the options to the canvas.
+//
+// Returns: (fan, length of the short columns relative to the long ones)
+fn fan_shape(
+    options: &FanOptions,
+    canvas_width: f64,
+    canvas_height: f64,
+) -> Result<(FanShape, f64), AppError> {
+    let sweep = options.sweep.unwrap_or(120.);
+    let inner_ratio = options.inner_ratio.unwrap_or(0.4);
+    let short_ratio = options.short_ratio.unwrap_or(0.5);
+    if !(sweep > 0. && sweep <= 180.) {
+        return Err(AppError::InvalidLayout(
+            "fan sweep must be within (0, 180] degrees".to_string(),
+        ));
+    }
+    if !(0. ..0.9).contains(&inner_ratio) {
+        return Err(AppError::InvalidLayout(
+            "fan inner ratio must be within [0, 0.9)".to_string(),
+        ));
+    }
+    if !(short_ratio > 0. && short_ratio <= 1.) {
+        return Err(AppError::InvalidLayout(
+            "fan short ratio must be within (0, 1]".to_string(),
+        ));
+    }
+
+    Ok((
+        layout::fit_fan(canvas_width, canvas_height, sweep, inner_ratio),
+        short_ratio,
+    ))
+}
+
+// Derive the inscription layout from the body one.
 //
-// Returns: (H_max, layout_map)
-fn calculate_layout_data(heights: &[f64], k: usize) -> (f64, Vec<usize>) {
-    let mut current_column_heights = vec![0.0; k];
-    let mut layout_map = Vec::with_capacity(heights.len());
-
-    for &h in heights {
-        // Find the index of the shortest column
-        let mut min_height = f64::INFINITY;
-        let mut min_index = 0;
-
-        for (i, &col_height) in current_column_heights.iter().enumerate() {
-            if col_height < min_height {
-                min_height = col_height;
-                min_index = i;
+// Returns: (inscription config, gap to the body, alignment)
+fn inscription_config(
+    options: &InscriptionOptions,
+    body: &LayoutConfig,
+) -> Result<(LayoutConfig, f64, InscriptionAlign), AppError> {
+    let relative_size = options.relative_size.unwrap_or(0.6);
+    if !(relative_size > 0. && relative_size <= 1.) {
+        return Err(AppError::InvalidLayout(
+            "inscription relative size must be within (0, 1]".to_string(),
+        ));
+    }
+    let gap = match options.gap {
+        Some(gap) if gap < 0 => {
+            return Err(AppError::InvalidLayout(
+                "inscription gap cannot be negative".to_string(),
+            ));
+        }
+        Some(gap) => gap as f64,
+        None => body.word_size / 2.,
+    };
+    let align = options
+        .align
+        .as_deref()
+        .map_or(Ok(InscriptionAlign::default()), InscriptionAlign::from_str)?;
+
+    Ok((
+        LayoutConfig {
+            canvas_width: body.canvas_width,
+            canvas_height: body.canvas_height,
+            word_size: (body.word_size * relative_size).max(1.),
+            letter_space: body.letter_space * relative_size,
+            line_space: body.line_space * relative_size,
+            fixed_space: body.fixed_space,
+            writing_mode: body.writing_mode,
+        },
+        gap,
+        align,
+    ))
+}
+
+/// Compute the position of every word of the poem, in right-to-left vertical columns by default.
+///
+/// When a subject is given, its inscription is placed beside the body and the
+/// whole piece is centred on the canvas.
+pub async fn compose_poem_static_layout(req: LayoutRequest) -> Result<LayoutData, AppError> {
+    if req.width <= 0 || req.height <= 0 {
+        return Err(AppError::InvalidLayout(
+            "canvas dimensions must be positive".to_string(),
+        ));
+    }
+    if req.letter_space < 0 || req.line_space < 0 {
+        return Err(AppError::InvalidLayout(
+            "spacing cannot be negative".to_string(),
+        ));
+    }
+
+    if req.fan.is_some() && !req.subject.is_empty() {
+        return Err(AppError::InvalidLayout(
+            "a fan cannot hold an inscription".to_string(),
+        ));
+    }
+
+    let font_type = CalliFont::from_str(&req.font_type)?;
+    let punctuation = parse_punctuation_mode(req.punctuation.as_deref())?;
+    let mut blob_config = BlobStorageConfig::from_local_env()?;
+    blob_config.set_char_mapping(!req.exact_chars);
+    let (items, mut substitutions) =
+        load_layout_items(&blob_config, font_type, &req.content, punctuation, "word").await?;
+    let mut mappings = char_mappings(
+        &blob_config,
+        "word",
+        text::drawn_chars(&req.content, punctuation)
+            .into_iter()
+            .map(|word| (font_type, word)),
+    )
+    .await?;
+
+    let mut config = LayoutConfig {
+        canvas_width: req.width as f64,
+        canvas_height: req.height as f64,
+        word_size: 0.,
+        letter_space: req.letter_space as f64,
+        line_space: req.line_space as f64,
+        fixed_space: parse_flag(&req.fixed_space),
+        writing_mode: match &req.writing_mode {
+            Some(mode) => WritingMode::from_str(mode)?,
+            None => WritingMode::default(),
+        },
+    };
+    let fit_to_canvas = match req.word_size {
+        _ if req.auto_fit => true,
+        Some(size) if size > 0 => {
+            config.word_size = size as f64;
+            false
+        }
+        Some(_) => {
+            return Err(AppError::InvalidLayout(
+                "word size must be positive".to_string(),
+            ));
+        }
+        None => true,
+    };
+    if fit_to_canvas && req.fan.is_none() {
+        config.word_size = layout::fit_word_size(&items, &config);
+    }
+
+    let inscription = if req.subject.is_empty() {
+        None
+    } else {
+        let sub_font_type = match &req.subject_font_type {
+            Some(font) => CalliFont::from_str(font)?,
+            None => font_type,
+        };
+        let (subject_items, subject_substitutions) = load_layout_items(
+            &blob_config,
+            sub_font_type,
+            &req.subject,
+            punctuation,
+            "subject",
+        )
+        .await?;
+        substitutions.extend(subject_substitutions);
+        mappings.extend(
+            char_mappings(
+                &blob_config,
+                "subject",
+                text::drawn_chars(&req.subject, punctuation)
+                    .into_iter()
+                    .map(|word| (sub_font_type, word)),
+            )
+            .await?,
+        );
+        let options = req.inscription.unwrap_or_default();
+
+        if fit_to_canvas {
+            // Leave room for one inscription line beside the body, then fit again.
+            let (sub_config, gap, _) = inscription_config(&options, &config)?;
+            let reserved = gap + sub_config.word_size;
+            let (canvas_width, canvas_height) = (config.canvas_width, config.canvas_height);
+            if config.writing_mode.is_vertical() {
+                config.canvas_width = (canvas_width - reserved).max(1.);
+            } else {
+                config.canvas_height = (canvas_height - reserved).max(1.);
             }
+            config.word_size = layout::fit_word_size(&items, &config);
+            (config.canvas_width, config.canvas_height) = (canvas_width, canvas_height);
+        }
+        Some((subject_items, options))
+    };
+
+    let mut fan = None;
+    let mut word = match &req.fan {
+        Some(_) if !config.writing_mode.is_vertical() => {
+            return Err(AppError::InvalidLayout(
+                "a fan only holds vertical columns".to_string(),
+            ));
         }
+        Some(options) => {
+            let (shape, short_ratio) =
+                fan_shape(options, config.canvas_width, config.canvas_height)?;
+            let (word_size, placed) =
+                layout::layout_fan(&items, &config, &shape, short_ratio, fit_to_canvas);
+            config.word_size = word_size;
+            fan = Some(shape);
+            placed
+        }
+        None => layout::layout_glyphs(&items, &config),
+    };
+    let mut subject = Vec::new();
+    if let Some((subject_items, options)) = inscription {
+        let boxes: Vec<(f64, f64, f64, f64)> = word
+            .iter()
+            .map(|w| (w.pos_x, w.pos_y, w.width as f64, w.height as f64))
+            .collect();
+        let body = layout::bounds(&boxes).unwrap_or((0., 0., 0., 0.));
+        let (sub_config, gap, align) = inscription_config(&options, &config)?;
+        subject = layout::layout_inscription(&subject_items, body, gap, align, &sub_config);
+
+        // Centre the body and the inscription together.
+        let body_len = word.len();
+        word.append(&mut subject);
+        layout::center_on_canvas(&mut word, config.canvas_width, config.canvas_height);
+        subject = word.split_off(body_len);
+    }
+
+    let largest_glyph = items
+        .iter()
+        .filter_map(|item| match *item {
+            LayoutItem::Glyph((w, _)) if config.writing_mode.is_vertical() => Some(w),
+            LayoutItem::Glyph((_, h)) => Some(h),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    Ok(LayoutData {
+        letter_space: req.letter_space,
+        line_space: req.line_space,
+        columns: word.iter().map(|w| w.line + 1).max().unwrap_or(0),
+        scale: if largest_glyph > 0 {
+            config.word_size / largest_glyph as f64
+        } else {
+            0.
+        },
+        word,
+        subject,
+        fan,
+        substitutions,
+        mappings,
+    })
+}
+
+/// Check which fonts hold a drawing of every distinct character of the content,
+/// following the same lookup as the animation without downloading any frame.
+pub async fn check_glyph_coverage(req: &GlyphCheckRequest) -> Result<Vec<GlyphCoverage>, AppError> {
+    let font_types = req
+        .font_types
+        .iter()
+        .map(|font| CalliFont::from_str(font))
+        .collect::<Result<Vec<_>, _>>()?;
+    let punctuation = parse_punctuation_mode(req.punctuation.as_deref())?;
+    let mut blob_config = BlobStorageConfig::from_local_env()?;
+    blob_config.set_char_mapping(!req.exact_chars);
+
+    let mut seen = HashSet::new();
+    let mut coverage = Vec::new();
+    for word in text::drawn_chars(&req.content, punctuation) {
+        if word.is_whitespace() || !seen.insert(word) {
+            continue;
+        }
+
+        // Look the word up once in each requested font and its fallback fonts.
+        let mut sources = HashMap::new();
+        for font_type in &font_types {
+            for font in std::iter::once(font_type).chain(blob_config.fallback_chain(font_type)) {
+                if !sources.contains_key(font) {
+                    sources.insert(*font, blob_config.glyph_source(font, word).await?);
+                }
+            }
+        }
+
+        let mut fonts = Vec::with_capacity(font_types.len());
+        for font_type in &font_types {
+            let (source, fallback_font) =
+                classify_glyph(*font_type, blob_config.fallback_chain(font_type), &sources);
+            fonts.push(FontCoverage {
+                font_type: font_type.to_string(),
+                lookup: blob_config.lookup_form(font_type, word).await?,
+                source: source.to_string(),
+                fallback_font: fallback_font.map(|font| font.to_string()),
+            });
+        }
+        coverage.push(GlyphCoverage { word, fonts });
+    }
+
+    Ok(coverage)
+}
+
+// Source of the drawing of a word in the font, along with the first fallback
+// font drawing it when the font has none, given its source in the fonts looked at.
+fn classify_glyph(
+    font_type: CalliFont,
+    fallbacks: &[CalliFont],
+    sources: &HashMap<CalliFont, GlyphSource>,
+) -> (GlyphSource, Option<CalliFont>) {
+    let is_drawn = |font: &CalliFont| {
+        sources
+            .get(font)
+            .is_some_and(|source| *source != GlyphSource::Missing)
+    };
+    if is_drawn(&font_type) {
+        (sources[&font_type], None)
+    } else {
+        (
+            GlyphSource::Missing,
+            fallbacks.iter().copied().find(|font| is_drawn(font)),
+        )
+    }
+}
+
+/// Lay out a couplet on one canvas, its three pieces sharing one glyph size.
+pub async fn compose_couplet_layout(req: &CoupletRequest) -> Result<CoupletData, AppError> {
+    if req.width <= 0 || req.height <= 0 {
+        return Err(AppError::InvalidLayout(
+            "canvas dimensions must be positive".to_string(),
+        ));
+    }
+    if req.letter_space < 0 || req.line_space < 0 {
+        return Err(AppError::InvalidLayout(
+            "spacing cannot be negative".to_string(),
+        ));
+    }
+    if req.word_size.is_some_and(|size| size <= 0) {
+        return Err(AppError::InvalidLayout(
+            "word size must be positive".to_string(),
+        ));
+    }
+
+    let font_type = CalliFont::from_str(&req.font_type)?;
+    let mut blob_config = BlobStorageConfig::from_local_env()?;
+    blob_config.set_char_mapping(!req.exact_chars);
+    let punctuation = PunctuationMode::Drop;
+    let (upper, mut substitutions) = load_layout_items(
+        &blob_config,
+        font_type,
+        &req.upper_line,
+        punctuation,
+        "upper",
+    )
+    .await?;
+    let (lower, lower_substitutions) = load_layout_items(
+        &blob_config,
+        font_type,
+        &req.lower_line,
+        punctuation,
+        "lower",
+    )
+    .await?;
+    let (banner, banner_substitutions) =
+        load_layout_items(&blob_config, font_type, &req.banner, punctuation, "banner").await?;
+    substitutions.extend(lower_substitutions);
+    substitutions.extend(banner_substitutions);
+    let mut mappings = Vec::new();
+    for (list, line) in [
+        ("upper", &req.upper_line),
+        ("lower", &req.lower_line),
+        ("banner", &req.banner),
+    ] {
+        let words = text::drawn_chars(line, punctuation);
+        let words = words.into_iter().map(|word| (font_type, word));
+        mappings.extend(char_mappings(&blob_config, list, words).await?);
+    }
+
+    let config = LayoutConfig {
+        canvas_width: req.width as f64,
+        canvas_height: req.height as f64,
+        word_size: req.word_size.unwrap_or(1) as f64,
+        letter_space: req.letter_space as f64,
+        line_space: req.line_space as f64,
+        fixed_space: parse_flag(&req.fixed_space),
+        writing_mode: WritingMode::VerticalRtl,
+    };
+    let placement =
+        layout::layout_couplet(&upper, &lower, &banner, &config, req.word_size.is_none());
+
+    let margin = config.line_space;
+    let piece = |word: Vec<StaticSubject>| {
+        let boxes: Vec<(f64, f64, f64, f64)> = word
+            .iter()
+            .map(|w| (w.pos_x, w.pos_y, w.width as f64, w.height as f64))
+            .collect();
+        let (left, top, right, bottom) = layout::bounds(&boxes).unwrap_or((0., 0., 0., 0.));
+        CoupletPiece {
+            pos_x: left - margin,
+            pos_y: top - margin,
+            width: (right - left + 2. * margin).round() as isize,
+            height: (bottom - top + 2. * margin).round() as isize,
+            word,
+        }
+    };
+
+    Ok(CoupletData {
+        word_size: placement.word_size as isize,
+        upper: piece(placement.upper),
+        lower: piece(placement.lower),
+        banner: piece(placement.banner),
+        substitutions,
+        mappings,
+    })
+}
+
+/// Prepares the animation of a couplet on one canvas: the upper line, the
+/// lower line, then the banner.
+pub async fn prepare_couplet_animation(
+    req: CoupletAnimationRequest,
+    tree: &Keyspace,
+) -> Result<PendingAnimation, AppError> {
+    let data = compose_couplet_layout(&req.couplet).await?;
+    let couplet = req.couplet;
+    let word_list = [data.upper.word, data.lower.word, data.banner.word]
+        .into_iter()
+        .flatten()
+        .map(AnimateSubject::from)
+        .collect();
+
+    prepare_poem_animation(
+        AnimationRequest {
+            task_id: req.task_id,
+            subject: String::new(),
+            subject_font_type: couplet.font_type.clone(),
+            subject_list: Vec::new(),
+            // Breaks are not drawn, they only keep the lines apart.
+            content: format!(
+                "{}\n{}\n{}",
+                couplet.upper_line, couplet.lower_line, couplet.banner
+            ),
+            font_type: couplet.font_type,
+            word_list,
+            width: couplet.width,
+            height: couplet.height,
+            fps: req.fps,
+            writing_mode: None,
+            punctuation: None,
+            inscription: None,
+            seal: None,
+            fan: None,
+            variant_seed: None,
+            exact_chars: couplet.exact_chars,
+            timing: req.timing,
+            order: None,
+            sequence: None,
+            budget: None,
+            encoding: req.encoding,
+            format: req.format,
+        },
+        tree,
+    )
+    .await
+}
+
+fn init_user_cache(tree: &Keyspace, task_id: &str) -> Result<(), AppError> {
+    if !tree.contains_key(task_id)? {
+        tree.insert(task_id, 0_isize.to_be_bytes())?;
+        Ok(())
+    } else {
+        Err(AppError::TaskIdInUse)
+    }
+}
+
+#[allow(clippy::collapsible_match)]
+pub fn check_update(
+    hashset: &mut HashSet<usize>,
+    tree: &Keyspace,
+    task_id: &str,
+    divpart: usize,
+) -> Result<(), AppError> {
+    match divpart {
+        // Finished.
+        100 => {
+            if !hashset.contains(&100) {
+                tree.insert(task_id, "100")?;
+                hashset.insert(100);
+            }
+        }
+        // 3 quarter
+        75 => {
+            if !hashset.contains(&75) {
+                tree.insert(task_id, "75")?;
+                hashset.insert(75);
+            }
+        }
+        // Half
+        50 => {
+            if !hashset.contains(&50) {
+                tree.insert(task_id, "50")?;
+                hashset.insert(50);
+            }
+        }
+        // Quarter
+        25 => {
+            if !hashset.contains(&25) {
+                tree.insert(task_id, "25")?;
+                hashset.insert(25);
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Pair each drawn character of the content with the font of its layer,
+// `font_type` when the layer has none of its own, and the variant it picks.
+fn layer_words(
+    content: &str,
+    layers: &[AnimateSubject],
+    font_type: CalliFont,
+    punctuation: PunctuationMode,
+) -> Result<Vec<(CalliFont, char, Option<u32>)>, AppError> {
+    text::drawn_chars(content, punctuation)
+        .into_iter()
+        .enumerate()
+        .map(|(idx, word)| {
+            let layer = layers.get(idx);
+            let variant = layer.and_then(|l| l.variant);
+            match layer.and_then(|l| l.font_type.as_deref()) {
+                Some(font) => Ok((CalliFont::from_str(font)?, word, variant)),
+                None => Ok((font_type, word, variant)),
+            }
+        })
+        .collect()
+}
+
+// Variant drawn for the n-th occurrence of a word: a starting variant drawn
+// from the seed, then the next ones in turn so that repetitions differ.
+fn seeded_variant(seed: u64, font_type: CalliFont, word: char, occurrence: u64, count: u32) -> u32 {
+    // SplitMix64 keeps the choice stable across platforms and releases.
+    let mut z = seed ^ ((font_type as u64) << 32) ^ word as u64;
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    (z.wrapping_add(occurrence) % count as u64) as u32
+}
+
+// Settle the variant of every word: the one picked by its layer, otherwise
+// the default one, or with a seed, a different one for each repetition.
+async fn choose_variants(
+    blob_config: &BlobStorageConfig,
+    words: Vec<(CalliFont, char, Option<u32>)>,
+    seed: Option<u64>,
+) -> Result<Vec<GlyphKey>, AppError> {
+    let mut counts: HashMap<(CalliFont, char), u32> = HashMap::new();
+    let mut occurrences: HashMap<(CalliFont, char), u64> = HashMap::new();
+    let mut keys = Vec::with_capacity(words.len());
+
+    for (font_type, word, variant) in words {
+        let variant = match (variant, seed) {
+            (Some(variant), _) => variant,
+            (None, None) => 0,
+            (None, Some(seed)) => {
+                let count = match counts.get(&(font_type, word)) {
+                    Some(&count) => count,
+                    None => {
+                        let count = blob_config.count_variants(&font_type, word).await?;
+                        counts.insert((font_type, word), count);
+                        count
+                    }
+                };
+                let occurrence = occurrences.entry((font_type, word)).or_insert(0);
+                let variant = seeded_variant(seed, font_type, word, *occurrence, count);
+                *occurrence += 1;
+                variant
+            }
+        };
+        keys.push((font_type, word, variant));
+    }
+
+    Ok(keys)
+}
+
+// Indices of the layers in the order they are drawn: as listed, or in the
+// reading order of the writing mode when one is given.
+fn drawing_order(layers: &[AnimateSubject], writing_mode: Option<WritingMode>) -> Vec<usize> {
+    match writing_mode {
+        Some(mode) => {
+            let boxes: Vec<(f64, f64, f64, f64)> = layers.iter().map(layer_box).collect();
+            layout::reading_order(&boxes, mode)
+        }
+        None => (0..layers.len()).collect(),
+    }
+}
+
+// Box of a layer on the canvas, as placed by the layout.
+fn layer_box(l: &AnimateSubject) -> (f64, f64, f64, f64) {
+    (
+        l.pos_x + l.modify_x,
+        l.pos_y,
+        l.width as f64,
+        l.height as f64,
+    )
+}
+
+/// A layer of the animation request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DrawnLayer {
+    Word(usize),
+    Subject(usize),
+}
+
+// Groups of layers in the order they are written, the layers of a group at the same time.
+fn drawing_steps(
+    order: AnimationOrder,
+    words: &[AnimateSubject],
+    subjects: &[AnimateSubject],
+    writing_mode: Option<WritingMode>,
+    sequence: Option<&[usize]>,
+) -> Result<Vec<Vec<DrawnLayer>>, AppError> {
+    let body = drawing_order(words, writing_mode)
+        .into_iter()
+        .map(DrawnLayer::Word);
+    let subject = drawing_order(subjects, writing_mode)
+        .into_iter()
+        .map(DrawnLayer::Subject);
+
+    let steps = match order {
+        AnimationOrder::Sequential => body.chain(subject).map(|l| vec![l]).collect(),
+        AnimationOrder::SubjectFirst => subject.chain(body).map(|l| vec![l]).collect(),
+        AnimationOrder::Reverse => body.chain(subject).rev().map(|l| vec![l]).collect(),
+        AnimationOrder::Simultaneous => vec![body.chain(subject).collect()],
+        AnimationOrder::Columns => {
+            // Lines are told apart even when the words are drawn in list order.
+            let mode = writing_mode.unwrap_or_default();
+            let lines = |layers: &[AnimateSubject]| {
+                let boxes: Vec<(f64, f64, f64, f64)> = layers.iter().map(layer_box).collect();
+                layout::reading_lines(&boxes, mode)
+            };
+            lines(words)
+                .into_iter()
+                .map(|line| line.into_iter().map(DrawnLayer::Word).collect())
+                .chain(
+                    lines(subjects)
+                        .into_iter()
+                        .map(|line| line.into_iter().map(DrawnLayer::Subject).collect()),
+                )
+                .collect()
+        }
+        AnimationOrder::Explicit => {
+            let Some(sequence) = sequence else {
+                return Err(AppError::InvalidOrder(
+                    "the explicit order needs a sequence".to_string(),
+                ));
+            };
+            sequence
+                .iter()
+                .map(|&idx| match idx.checked_sub(words.len()) {
+                    None => Ok(vec![DrawnLayer::Word(idx)]),
+                    Some(sub_idx) if sub_idx < subjects.len() => {
+                        Ok(vec![DrawnLayer::Subject(sub_idx)])
+                    }
+                    Some(_) => Err(AppError::InvalidOrder(format!(
+                        "layer {idx} is out of the {} layers",
+                        words.len() + subjects.len()
+                    ))),
+                })
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    Ok(steps)
+}
+
+// Pause before drawing the next layer, the column pause when it starts a new line.
+fn pause_before(
+    timing: &TimingOptions,
+    previous: Option<&AnimateSubject>,
+    next: &AnimateSubject,
+    mode: WritingMode,
+) -> i32 {
+    match previous {
+        None => 0,
+        Some(previous) if layout::same_line(layer_box(previous), layer_box(next), mode) => {
+            i32::from(timing.char_pause_ms)
+        }
+        Some(_) => i32::from(timing.column_pause_ms),
+    }
+}
+
+// Number of frames kept for each step of the given lengths: fewer at higher
+// speeds, then thinned out in proportion to fit the allowance. A step
+// keeps at least its final frame, so an allowance below one frame a step
+// cannot be met.
+fn kept_frame_counts(
+    lengths: &[usize],
+    speed: f64,
+    allowance: Option<usize>,
+) -> Result<Vec<usize>, AppError> {
+    let mut kept: Vec<usize> = lengths
+        .iter()
+        .map(|&length| match length {
+            0 => 0,
+            _ => ((length as f64 / speed.max(1.)).ceil() as usize).clamp(1, length),
+        })
+        .collect();
+    let total = kept.iter().sum::<usize>();
+    if let Some(allowance) = allowance
+        && total > allowance
+    {
+        let steps = kept.iter().filter(|&&count| count > 0).count();
+        if allowance < steps {
+            return Err(AppError::InvalidBudget(format!(
+                "the budget leaves {allowance} stroke frames, fewer than the {steps} steps drawn"
+            )));
+        }
+        // The final frames are kept, the others share what the allowance leaves.
+        let ratio = (allowance - steps) as f64 / (total - steps) as f64;
+        for count in kept.iter_mut().filter(|count| **count > 0) {
+            *count = 1 + ((*count - 1) as f64 * ratio).floor() as usize;
+        }
+    }
+    Ok(kept)
+}
+
+// Allowance keeping about half the stroke frames kept within the given one,
+// `None` once the frames cannot be thinned out any further.
+fn halved_allowance(
+    lengths: &[usize],
+    speed: f64,
+    allowance: Option<usize>,
+) -> Result<Option<usize>, AppError> {
+    let frames = kept_frame_counts(lengths, speed, allowance)?;
+    let frames = frames.iter().sum::<usize>();
+    let least = lengths.iter().filter(|&&length| length > 0).count();
+    let halved = (frames / 2).max(least);
+    let fewer = kept_frame_counts(lengths, speed, Some(halved))?;
+
+    Ok((fewer.iter().sum::<usize>() < frames).then_some(halved))
+}
+
+// A step of the animation: the number of its layers, and the frames of those
+// with valid frames, resized to fit them, along with their layer.
+type DrawnStep = (usize, Vec<(AnimateSubject, Arc<Vec<RgbaImage>>)>);
+
+// Spacing assumed between the words of an animation body, relative to their size.
+const BODY_SPACING: f64 = 0.2;
+
+// Place the subject of an animation beside its word list.
+fn animation_inscription(
+    req: &AnimationRequest,
+    options: &InscriptionOptions,
+    subject_strokes: &HashMap<GlyphKey, Vec<WordFrame>>,
+    subject_words: &[GlyphKey],
+    punctuation: PunctuationMode,
+    writing_mode: WritingMode,
+) -> Result<Vec<AnimateSubject>, AppError> {
+    let boxes: Vec<(f64, f64, f64, f64)> = req
+        .word_list
+        .iter()
+        .map(|l| {
+            (
+                l.pos_x + l.modify_x,
+                l.pos_y,
+                l.width as f64,
+                l.height as f64,
+            )
+        })
+        .collect();
+    let Some(body) = layout::bounds(&boxes) else {
+        return Err(AppError::InvalidLayout(
+            "an inscription needs a word list to be placed beside".to_string(),
+        ));
+    };
+
+    // The median line thickness stands for the body word size.
+    let mut thickness: Vec<f64> = boxes
+        .iter()
+        .map(|&(_, _, w, h)| if writing_mode.is_vertical() { w } else { h })
+        .collect();
+    thickness.sort_by(f64::total_cmp);
+    let word_size = thickness[thickness.len() / 2];
+    let body_config = LayoutConfig {
+        canvas_width: req.width as f64,
+        canvas_height: req.height as f64,
+        word_size,
+        letter_space: word_size * BODY_SPACING,
+        line_space: word_size * BODY_SPACING,
+        fixed_space: false,
+        writing_mode,
+    };
+    let (sub_config, gap, align) = inscription_config(options, &body_config)?;
+
+    let glyph_size = |word: char| {
+        subject_words
+            .iter()
+            .find(|key| key.1 == word)
+            .and_then(|key| subject_strokes.get(key))
+            .and_then(|frames| frames.last())
+            .map_or((0, 0), |frame| (frame.width, frame.height))
+    };
+    let items = layout_items(&text::tokenize(&req.subject, punctuation), glyph_size);
+
+    Ok(
+        layout::layout_inscription(&items, body, gap, align, &sub_config)
+            .into_iter()
+            .map(AnimateSubject::from)
+            .collect(),
+    )
+}
+
+// Pixels of the canvas lying on the paper of a fan, in row order.
+struct FanMask(Vec<bool>);
+
+impl FanMask {
+    fn new(fan: &FanShape, width: u32, height: u32) -> Self {
+        let inside = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| layout::fan_contains(fan, x as f64 + 0.5, y as f64 + 0.5))
+            .collect();
+        Self(inside)
+    }
+
+    // Leave only the paper of the fan, the rest of the canvas becomes
+    // transparent, clipping whatever was drawn past its edges.
+    fn apply(&self, canvas: &mut RgbaImage) {
+        for (pixel, _) in canvas
+            .pixels_mut()
+            .zip(&self.0)
+            .filter(|(_, inside)| !**inside)
+        {
+            *pixel = Rgba([255, 255, 255, 0]);
+        }
+    }
+}
+
+// Turn the image clockwise about its centre, growing it to hold the corners.
+fn rotate_about_center(img: &RgbaImage, degrees: f64) -> RgbaImage {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (img.width() as f64, img.height() as f64);
+    let rotated_width = (width * cos.abs() + height * sin.abs()).ceil() as u32;
+    let rotated_height = (width * sin.abs() + height * cos.abs()).ceil() as u32;
+
+    let mut rotated = RgbaImage::new(rotated_width, rotated_height);
+    for (x, y, pixel) in rotated.enumerate_pixels_mut() {
+        // Sample the source at the point turned back by the angle.
+        let dx = x as f64 + 0.5 - rotated_width as f64 / 2.;
+        let dy = y as f64 + 0.5 - rotated_height as f64 / 2.;
+        let source_x = dx * cos + dy * sin + width / 2. - 0.5;
+        let source_y = -dx * sin + dy * cos + height / 2. - 0.5;
+        if let Some(sample) = imageops::interpolate_bilinear(img, source_x as f32, source_y as f32)
+        {
+            *pixel = sample;
+        }
+    }
+    rotated
+}
+
+// Draw the frame of a layer at its position, turned by its rotation.
+fn overlay_layer(canvas: &mut RgbaImage, img: &RgbaImage, layer: &AnimateSubject) {
+    let (pos_x, pos_y) = (layer.pos_x + layer.modify_x, layer.pos_y);
+    if layer.rotation == 0. {
+        imageops::overlay(canvas, img, pos_x as i64, pos_y as i64);
+    } else {
+        let rotated = rotate_about_center(img, layer.rotation);
+        let centre_x = pos_x + img.width() as f64 / 2.;
+        let centre_y = pos_y + img.height() as f64 / 2.;
+        imageops::overlay(
+            canvas,
+            &rotated,
+            (centre_x - rotated.width() as f64 / 2.).round() as i64,
+            (centre_y - rotated.height() as f64 / 2.).round() as i64,
+        );
+    }
+}
+
+// Map the requested settings onto the options of the WebP encoder.
+fn encoder_options(options: &EncodingOptions) -> Result<EncoderOptions, AppError> {
+    let quality = options.quality.unwrap_or(75.);
+    if !(0. ..=100.).contains(&quality) {
+        return Err(AppError::InvalidEncoding(format!(
+            "the quality must be between 0 and 100, got {quality}"
+        )));
+    }
+    let method = options.method.unwrap_or(4);
+    if method > 6 {
+        return Err(AppError::InvalidEncoding(format!(
+            "the method must be between 0 and 6, got {method}"
+        )));
+    }
+    // libwebp wants the shortest distance above half the longest one and
+    // below it, which no distance does for an interval of 2.
+    let (kmin, kmax) = match options.key_frame_interval {
+        None | Some(0) => (0, 0),
+        Some(1) => (0, 1),
+        Some(2) => {
+            return Err(AppError::InvalidEncoding(
+                "the key frame interval cannot be 2, use 1 or at least 3".to_string(),
+            ));
+        }
+        Some(interval) => {
+            let kmax = interval as isize;
+            (kmax / 2 + 1, kmax)
+        }
+    };
+    let encoding_type = if options.lossless {
+        EncodingType::Lossless
+    } else {
+        EncodingType::Lossy(LossyEncodingConfig::default())
+    };
+
+    Ok(EncoderOptions {
+        kmin,
+        kmax,
+        encoding_config: Some(EncodingConfig {
+            encoding_type,
+            quality,
+            method,
+        }),
+        ..Default::default()
+    })
+}
+
+// Everything needed to draw the frames of an animation.
+struct AnimationPlan {
+    // Blank paper, with the fan mask and the seal unless it is animated.
+    paper: RgbaImage,
+    steps: Vec<DrawnStep>,
+    // Pause before each step.
+    pauses: Vec<i32>,
+    // Frames of each step.
+    lengths: Vec<usize>,
+    frame_delay_ms: i32,
+    lead_in_ms: i32,
+    final_hold_ms: i32,
+    // Seal stamped as the final frame.
+    seal: Option<Seal>,
+    // Paper of the fan, clipping every frame.
+    fan_mask: Option<FanMask>,
+}
+
+impl AnimationPlan {
+    // Frames drawn when keeping the given number of frames of each step.
+    fn frame_count(&self, kept: &[usize]) -> usize {
+        usize::from(self.lead_in_ms > 0)
+            + kept.iter().sum::<usize>()
+            + usize::from(self.seal.is_some())
+    }
+
+    // Draw the animation keeping the given number of frames of each step, handing
+    // every frame to the sink along with its timestamp. Returns the total duration.
+    fn render(
+        &self,
+        kept: &[usize],
+        mut on_layer: impl FnMut() -> Result<(), AppError>,
+        mut add_frame: impl FnMut(&RgbaImage, i32) -> Result<(), AppError>,
+    ) -> Result<i32, AppError> {
+        let mut canvas = self.paper.clone();
+        let mut current_timestamp = 0;
+        if self.lead_in_ms > 0 {
+            add_frame(&canvas, current_timestamp)?;
+            current_timestamp = later(current_timestamp, self.lead_in_ms)?;
+        }
+
+        let steps = self.steps.iter().zip(&self.pauses).zip(&self.lengths);
+        for ((((layer_count, drawn), pause), &length), &kept) in steps.zip(kept) {
+            for _ in 0..*layer_count {
+                on_layer()?;
+            }
+            if kept == 0 {
+                continue;
+            }
+            current_timestamp = later(current_timestamp, *pause)?;
+
+            // Next frame to draw of each word, skipped frames being merged into the kept ones.
+            let mut next_frames = vec![0; drawn.len()];
+            for n in 0..kept {
+                // Spread the kept frames evenly, ending on the final one.
+                let until = ((n + 1) * length).div_ceil(kept);
+                for ((layer, frames), next) in drawn.iter().zip(&mut next_frames) {
+                    let until = until.min(frames.len());
+                    for img in &frames[*next..until] {
+                        // Apply strokes onto the main canvas
+                        overlay_layer(&mut canvas, img, layer);
+                    }
+                    *next = until.max(*next);
+                }
+                if let Some(mask) = &self.fan_mask {
+                    mask.apply(&mut canvas);
+                }
+                add_frame(&canvas, current_timestamp)?;
+                // Advance the timestamp by the frame delay for the next frame
+                current_timestamp = later(current_timestamp, self.frame_delay_ms)?;
+            }
+        }
+
+        // Stamp the seal as the final frame.
+        if let Some(seal) = &self.seal {
+            seal.stamp(&mut canvas);
+            if let Some(mask) = &self.fan_mask {
+                mask.apply(&mut canvas);
+            }
+            add_frame(&canvas, current_timestamp)?;
+            current_timestamp = later(current_timestamp, self.frame_delay_ms)?;
+        }
+
+        // Hold the finished piece before the animation ends or loops.
+        later(current_timestamp, self.final_hold_ms)
+    }
+}
+
+// Timestamp the delay after the given one, refusing an animation too long to be timed.
+fn later(timestamp_ms: i32, delay_ms: i32) -> Result<i32, AppError> {
+    timestamp_ms.checked_add(delay_ms).ok_or_else(|| {
+        AppError::InvalidBudget("the animation lasts too long to be timed".to_string())
+    })
+}
 
-        current_column_heights[min_index] += h;
-        layout_map.push(min_index);
-    }
+// Encode the animation into WebP.
+//
+// Returns: (WebP data, frame count)
+fn encode_webp(
+    plan: &AnimationPlan,
+    kept: &[usize],
+    options: Option<EncoderOptions>,
+    on_layer: impl FnMut() -> Result<(), AppError>,
+) -> Result<(WebPData, usize), AppError> {
+    let dimensions = plan.paper.dimensions();
+    // Initialize the WebP Encoder, with the default config unless requested otherwise.
+    let mut encoder = match options {
+        Some(options) => Encoder::new_with_options(dimensions, options)?,
+        None => Encoder::new(dimensions)?,
+    };
+    let mut frame_count = 0;
+    let duration = plan.render(kept, on_layer, |canvas, timestamp| {
+        encoder.add_frame(canvas.as_raw(), timestamp)?;
+        frame_count += 1;
+        Ok(())
+    })?;
 
-    let h_max = current_column_heights.into_iter().fold(0.0, f64::max);
-    (h_max, layout_map)
+    // The last timestamp tells the encoder the total duration.
+    Ok((encoder.finalize(duration)?, frame_count))
 }
 
-// Calculates the final scaled (x, y) coordinate for the top-left corner of each word,
-// returning them as u32 pixel values after rounding.
-fn get_word_coordinates(
-    word_width: u32,
-    word_heights: &[u32],
-    s: f64,
-    k: usize,
-    final_layout_map: &[usize],
-) -> Vec<(u32, u32)> {
-    let word_width_f64 = word_width as f64;
-    let mut column_offsets = vec![0.0; k]; // Keep f64 offsets for precision
-    let mut scaled_coords = Vec::with_capacity(word_heights.len());
-    let scaled_word_width = word_width_f64 * s;
+// Encode the animation into GIF.
+//
+// Returns: (GIF data, frame count)
+fn encode_gif(
+    plan: &AnimationPlan,
+    kept: &[usize],
+    on_layer: impl FnMut() -> Result<(), AppError>,
+) -> Result<(Vec<u8>, usize), AppError> {
+    let (width, height) = plan.paper.dimensions();
+    let mut writer = GifWriter::new(width, height)?;
+    let duration = plan.render(kept, on_layer, |canvas, timestamp| {
+        writer.add_frame(canvas, timestamp)
+    })?;
+
+    writer.finish(duration)
+}
 
-    for (i, &h) in word_heights.iter().enumerate() {
-        let h_f64 = h as f64;
-        let column_index = final_layout_map[i];
+// Encode the animation into APNG.
+//
+// Returns: (APNG data, frame count)
+fn encode_apng(
+    plan: &AnimationPlan,
+    kept: &[usize],
+    on_layer: impl FnMut() -> Result<(), AppError>,
+) -> Result<(Vec<u8>, usize), AppError> {
+    let (width, height) = plan.paper.dimensions();
+    let mut data = Vec::new();
+    let mut writer = ApngWriter::new(&mut data, width, height, plan.frame_count(kept))?;
+    let duration = plan.render(kept, on_layer, |canvas, timestamp| {
+        writer.add_frame(canvas, timestamp)
+    })?;
+    let frame_count = writer.finish(duration)?;
+
+    Ok((data, frame_count))
+}
 
-        // X coordinate (f64 calculation)
-        let x_f64 = column_index as f64 * scaled_word_width;
+// Lower the quality, then the frames, of an animation until its file fits the
+// target size, starting from its encoding with the requested settings.
+// Lossless encoding gives way to lossy encoding when too large.
+fn fit_webp_to_size(
+    plan: &AnimationPlan,
+    speed: f64,
+    allowance: Option<usize>,
+    options: &EncodingOptions,
+    target: usize,
+    encoded: (WebPData, usize),
+) -> Result<(WebPData, usize, EncodingChoice), AppError> {
+    let method = options.method.unwrap_or(4);
+    let choice =
+        |quality: f32, lossless: bool, (data, frame_count): &(WebPData, usize)| EncodingChoice {
+            quality,
+            lossless,
+            method,
+            frame_count: *frame_count,
+            size: data.len(),
+        };
+    if encoded.0.len() <= target {
+        let choice = choice(options.quality.unwrap_or(75.), options.lossless, &encoded);
+        return Ok((encoded.0, encoded.1, choice));
+    }
 
-        // Y coordinate (f64 calculation)
-        let y_f64 = column_offsets[column_index];
+    // Lossy encoding at the given quality, keeping the stroke frames within the allowance.
+    let encode = |quality: f32, allowance: Option<usize>| {
+        let options = encoder_options(&EncodingOptions {
+            quality: Some(quality),
+            lossless: false,
+            method: Some(method),
+            key_frame_interval: options.key_frame_interval,
+            target_size: None,
+        })?;
+        let kept = kept_frame_counts(&plan.lengths, speed, allowance)?;
+        encode_webp(plan, &kept, Some(options), || Ok(()))
+    };
 
-        // Convert to u32 pixel coordinates
-        scaled_coords.push((x_f64 as u32, y_f64 as u32));
+    // Halve the stroke frames until the lowest quality fits, down to one frame a step.
+    let mut allowance = allowance;
+    let mut smallest = encode(0., allowance)?;
+    while smallest.0.len() > target {
+        let Some(halved) = halved_allowance(&plan.lengths, speed, allowance)? else {
+            return Err(AppError::InvalidEncoding(format!(
+                "the animation cannot fit into {target} bytes, taking at least {}",
+                smallest.0.len()
+            )));
+        };
+        allowance = Some(halved);
+        smallest = encode(0., allowance)?;
+    }
 
-        // Update the column offset
-        let scaled_height = h_f64 * s;
-        column_offsets[column_index] += scaled_height;
+    // Highest whole quality fitting with these frames, the lowest one being known to fit.
+    let (mut low, mut high) = (0, options.quality.unwrap_or(75.).clamp(0., 100.) as u32);
+    let mut best = smallest;
+    while low < high {
+        let quality = (low + high).div_ceil(2);
+        let encoded = encode(quality as f32, allowance)?;
+        if encoded.0.len() <= target {
+            low = quality;
+            best = encoded;
+        } else {
+            high = quality - 1;
+        }
     }
 
-    scaled_coords
+    let choice = choice(low as f32, false, &best);
+    Ok((best.0, best.1, choice))
 }
 
-pub async fn compose_poem_static_layout(mut selected_words: Vec<WordFrame>, canvas_width: u32, canvas_height: u32, fixed_space: bool) -> Result<(), AppError> {
-    let word_orig_width = selected_words[0].width;
-    let word_count = selected_words.len();
-    if fixed_space {
-        let word_orig_height = selected_words.iter().max_by(|x, y| x.height.cmp(&y.height));
+/// An encoded animation along with what was decided while rendering it.
+pub struct RenderedAnimation {
+    pub data: Vec<u8>,
+    pub format: AnimationFormat,
+    /// Words drawn in a fallback font.
+    pub substitutions: Vec<Substitution>,
+    /// Words looked up under their stored form.
+    pub mappings: Vec<CharMapping>,
+    /// Frames in the encoded animation.
+    pub frame_count: usize,
+    /// Settings chosen to meet the target size, if any.
+    pub encoding: Option<EncodingChoice>,
+}
 
+// An animation ready to be drawn, along with what was decided while preparing it.
+struct PreparedAnimation {
+    plan: AnimationPlan,
+    // Frames kept of each step.
+    kept: Vec<usize>,
+    speed: f64,
+    // Most stroke frames allowed by the budget.
+    allowance: Option<usize>,
+    // Layers drawn, counted by the progress.
+    layer_count: usize,
+    substitutions: Vec<Substitution>,
+    mappings: Vec<CharMapping>,
+}
 
-    } else {
-        // Convert u32 inputs to f64 for calculations involving ratios and scaling
-        let canvas_width_f64 = canvas_width as f64;
-        let canvas_height_f64 = canvas_height as f64;
-        let word_width_f64 = word_orig_width as f64;
-        // Convert heights for the helper function
-        let word_heights_f64: Vec<f64> = selected_words.iter().map(|&w| w.height as f64).collect();
+// Range the writing speed is held within, so that the slowest frame delay
+// stays far from overflowing the timestamps.
+const MIN_SPEED: f64 = 0.1;
+const MAX_SPEED: f64 = 10.;
+
+// Settings of an animation parsed from its request, so that a malformed
+// request is refused before its task id is claimed.
+struct AnimationSettings {
+    writing_mode: Option<WritingMode>,
+    punctuation: PunctuationMode,
+    order: AnimationOrder,
+    timing: TimingOptions,
+    budget: BudgetOptions,
+    speed: f64,
+    fan: Option<FanShape>,
+    // Font, character and variant of each layer of both lists.
+    content_words: Vec<(CalliFont, char, Option<u32>)>,
+    subject_words: Vec<(CalliFont, char, Option<u32>)>,
+}
 
-        let mut max_scaling_factor_s = 0.0;
-        let mut optimal_columns_k = 1;
-        let mut optimal_h_max = f64::INFINITY;
+fn animation_settings(req: &mut AnimationRequest) -> Result<AnimationSettings, AppError> {
+    let font_type = CalliFont::from_str(&req.font_type)?;
+    let sub_font_type = CalliFont::from_str(&req.subject_font_type)?;
+    // A sequence alone asks for the explicit order.
+    let order = match (req.order.as_deref(), &req.sequence) {
+        (Some(order), _) => AnimationOrder::from_str(order)?,
+        (None, Some(_)) => AnimationOrder::Explicit,
+        (None, None) => AnimationOrder::default(),
+    };
+    let writing_mode = req
+        .writing_mode
+        .as_deref()
+        .map(WritingMode::from_str)
+        .transpose()?;
+
+    // The n-th drawn character is matched with the n-th layer of the list.
+    let punctuation = parse_punctuation_mode(req.punctuation.as_deref())?;
+    let content_words = layer_words(&req.content, &req.word_list, font_type, punctuation)?;
+    // A computed inscription replaces the subject layers with their fonts.
+    let subject_layers = match req.inscription {
+        Some(_) => &[][..],
+        None => &req.subject_list[..],
+    };
+    let subject_words = layer_words(&req.subject, subject_layers, sub_font_type, punctuation)?;
+
+    let budget = req.budget.take().unwrap_or_default();
+    let speed = budget.speed.unwrap_or(1.);
+    if !speed.is_finite() || speed <= 0. {
+        return Err(AppError::InvalidBudget(format!(
+            "the speed must be positive, got {speed}"
+        )));
+    }
+    let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    let fan = req
+        .fan
+        .as_ref()
+        .map(|options| fan_shape(options, req.width as f64, req.height as f64))
+        .transpose()?
+        .map(|(fan, _)| fan);
+
+    Ok(AnimationSettings {
+        writing_mode,
+        punctuation,
+        order,
+        timing: req.timing.take().unwrap_or_default(),
+        budget,
+        speed,
+        fan,
+        content_words,
+        subject_words,
+    })
+}
 
-        // Iterate through all possible column counts (K) from 1 up to N
-        (1..=word_count).for_each(|k| {
+/// Entry of a task in the progress tree, removed when the task ends whether
+/// it succeeded or not.
+struct TaskEntry {
+    tree: Keyspace,
+    task_id: String,
+    removed: bool,
+}
 
-        });
+impl TaskEntry {
+    // Claim the task id, refusing one still in use.
+    fn claim(tree: &Keyspace, task_id: &str) -> Result<Self, AppError> {
+        init_user_cache(tree, task_id)?;
+        Ok(Self {
+            tree: tree.clone(),
+            task_id: task_id.to_string(),
+            removed: false,
+        })
     }
 
-    todo!();
-}
-*/
+    // Progress of the task, updated as each layer is drawn.
+    fn progress(&self, layer_count: usize) -> impl FnMut() -> Result<(), AppError> + '_ {
+        task_progress(&self.tree, &self.task_id, layer_count)
+    }
 
-fn init_user_cache(tree: &Keyspace, task_id: &str) -> Result<(), AppError> {
-    if !tree.contains_key(task_id)? {
-        tree.insert(task_id, 0_isize.to_be_bytes())?;
+    // Remove the entry as finished.
+    fn finish(mut self) -> Result<(), AppError> {
+        self.removed = true;
+        self.tree.remove(self.task_id.as_str())?;
         Ok(())
-    } else {
-        Err(AppError::TaskIdInUse)
     }
 }
 
-pub fn check_update(
-    hashset: &mut HashSet<usize>,
-    tree: &Keyspace,
-    task_id: &str,
-    divpart: usize,
-) -> Result<(), AppError> {
-    match divpart {
-        // Finished.
-        100 => {
-            if !hashset.contains(&100) {
-                tree.insert(task_id, "100")?;
-                hashset.insert(100);
-            }
-        }
-        // 3 quarter
-        75 => {
-            if !hashset.contains(&75) {
-                tree.insert(task_id, "75")?;
-                hashset.insert(75);
-            }
-        }
-        // Half
-        50 => {
-            if !hashset.contains(&50) {
-                tree.insert(task_id, "50")?;
-                hashset.insert(50);
-            }
-        }
-        // Quarter
-        25 => {
-            if !hashset.contains(&25) {
-                tree.insert(task_id, "25")?;
-                hashset.insert(25);
-            }
+impl Drop for TaskEntry {
+    fn drop(&mut self) {
+        if !self.removed {
+            // Nothing is left to report the failure to, the task already failed.
+            let _ = self.tree.remove(self.task_id.as_str());
         }
-        _ => (),
     }
-
-    Ok(())
 }
 
-pub async fn generate_poem_animation_webp(
-    req: AnimationRequest,
-    tree: &Keyspace,
-) -> Result<WebPData, AppError> {
-    // Ensure the task id is not in used.
-    init_user_cache(tree, &req.task_id)?;
-
+// Load the glyphs of an animation and settle its layers, order, timing and frame budget.
+async fn prepare_animation(
+    req: &mut AnimationRequest,
+    settings: AnimationSettings,
+) -> Result<PreparedAnimation, AppError> {
+    let AnimationSettings {
+        writing_mode,
+        punctuation,
+        order,
+        timing,
+        budget,
+        speed,
+        fan,
+        content_words,
+        subject_words,
+    } = settings;
     let canvas_width = req.width as u32;
     let canvas_height = req.height as u32;
-    let font_type = CalliFont::from_str(&req.font_type)?;
-    let sub_font_type = CalliFont::from_str(&req.subject_font_type)?;
-    let blob_config = BlobStorageConfig::from_local_env()?;
+    let mut blob_config = BlobStorageConfig::from_local_env()?;
+    blob_config.set_char_mapping(!req.exact_chars);
     let frame_delay_ms = (1000 / req.fps).abs() as i32;
 
-    let mut content_strokes = blob_config
-        .get_poem_frames_by_font_type(&font_type, &req.content)
-        .await?;
-    let mut subject_strokes = blob_config
-        .get_poem_frames_by_font_type(&sub_font_type, &req.subject)
+    // Settle the variants of both lists together, so that repetitions differ across them.
+    let content_len = content_words.len();
+    let mut content_words = choose_variants(
+        &blob_config,
+        content_words.into_iter().chain(subject_words).collect(),
+        req.variant_seed,
+    )
+    .await?;
+    let subject_words = content_words.split_off(content_len);
+
+    let (strokes, substituted) = blob_config
+        .get_poem_frames_by_font_type(content_words.iter().chain(&subject_words).copied())
         .await?;
+    let mut substitutions: Vec<Substitution> =
+        [("word", &content_words), ("subject", &subject_words)]
+            .into_iter()
+            .flat_map(|(list, words)| {
+                words.iter().enumerate().filter_map(|(position, key)| {
+                    let fallback = substituted.get(key)?;
+                    Some(substitution(list, position, key.0, *fallback))
+                })
+            })
+            .collect();
+    let mut mappings = Vec::new();
+    for (list, words) in [("word", &content_words), ("subject", &subject_words)] {
+        let words = words.iter().map(|&(font_type, word, _)| (font_type, word));
+        mappings.extend(char_mappings(&blob_config, list, words).await?);
+    }
+    let content_count = content_words.iter().collect::<HashSet<_>>().len();
+    let subject_count = subject_words.iter().collect::<HashSet<_>>().len();
+
+    // Replace the subject list by an inscription computed beside the body.
+    if let Some(options) = req.inscription.take() {
+        req.subject_list = animation_inscription(
+            req,
+            &options,
+            &strokes,
+            &subject_words,
+            punctuation,
+            writing_mode.unwrap_or_default(),
+        )?;
+    }
 
     // The current WebP encoder only accepts rgbA input.
     // Todo: Change to lumaA for perfomance improvement.
     let mut main_canvas =
         RgbaImage::from_pixel(canvas_width, canvas_height, Rgba([255, 255, 255, 255]));
+    let fan_mask = fan
+        .as_ref()
+        .map(|fan| FanMask::new(fan, canvas_width, canvas_height));
+
+    let seal = match &req.seal {
+        Some(options) => {
+            let inscription: Vec<(f64, f64, f64, f64)> = req
+                .subject_list
+                .iter()
+                .map(|l| {
+                    (
+                        l.pos_x + l.modify_x,
+                        l.pos_y,
+                        l.width as f64,
+                        l.height as f64,
+                    )
+                })
+                .collect();
+            let mut seal = Seal::from_options(
+                options,
+                &blob_config,
+                (canvas_width, canvas_height),
+                &inscription,
+            )
+            .await?;
+            substitutions.append(&mut seal.substitutions);
+            Some(seal)
+        }
+        None => None,
+    };
+    // Stamp the seal on the blank paper unless it is the final frame.
+    if let Some(seal) = seal.as_ref().filter(|seal| !seal.animate) {
+        seal.stamp(&mut main_canvas);
+    }
+    if let Some(mask) = &fan_mask {
+        mask.apply(&mut main_canvas);
+    }
 
-    // Initialize the WebP Encoder with default config.
-    let mut encoder = Encoder::new((canvas_width, canvas_height))?;
-    let mut current_timestamp = 0;
+    if req.word_list.len() < content_count {
+        return Err(AppError::InvalidFileName(
+            "WordList contains illegal characters".to_string(),
+        ));
+    }
+    // The subject is not drawn when its list misses some of its words.
+    let subject_len = if req.subject_list.len() >= subject_count {
+        req.subject_list.len()
+    } else {
+        0
+    };
+    let steps = drawing_steps(
+        order,
+        &req.word_list,
+        &req.subject_list[..subject_len],
+        writing_mode,
+        req.sequence.as_deref(),
+    )?;
+    // Frames are resized once, words drawn more than once at the same size
+    // sharing them, however many times the animation is rendered.
+    let mut resized: HashMap<(GlyphKey, isize, isize), Arc<Vec<RgbaImage>>> = HashMap::new();
+    let drawn_steps: Vec<DrawnStep> = steps
+        .iter()
+        .map(|step| {
+            let drawn = step
+                .iter()
+                .filter_map(|drawn_layer| {
+                    let (layer, key) = match *drawn_layer {
+                        DrawnLayer::Word(idx) => (&req.word_list[idx], content_words.get(idx)),
+                        DrawnLayer::Subject(idx) => {
+                            (&req.subject_list[idx], subject_words.get(idx))
+                        }
+                    };
+                    let key = *key?;
+                    let frames = strokes.get(&key)?;
+                    let frames = resized
+                        .entry((key, layer.width, layer.height))
+                        .or_insert_with(|| {
+                            let frames = frames
+                                .iter()
+                                .map(|frame| {
+                                    imageops::resize(
+                                        &frame.img,
+                                        layer.width as u32,
+                                        layer.height as u32,
+                                        FilterType::Gaussian,
+                                    )
+                                })
+                                .collect();
+                            Arc::new(frames)
+                        });
+                    Some((layer.clone(), Arc::clone(frames)))
+                })
+                .collect();
+            (step.len(), drawn)
+        })
+        .collect();
+
+    // Lines are told apart as in the reading order, columns by default.
+    let line_mode = writing_mode.unwrap_or_default();
+    let mut previous_layer = None;
+    let pauses: Vec<i32> = drawn_steps
+        .iter()
+        .map(|(_, drawn)| {
+            let (Some((first, _)), Some((last, _))) = (drawn.first(), drawn.last()) else {
+                return 0;
+            };
+            let pause = pause_before(&timing, previous_layer, first, line_mode);
+            previous_layer = Some(last);
+            pause
+        })
+        .collect();
+    // Words written together last as long as the longest one.
+    let lengths: Vec<usize> = drawn_steps
+        .iter()
+        .map(|(_, drawn)| drawn.iter().map(|(_, frames)| frames.len()).max())
+        .map(|length| length.unwrap_or(0))
+        .collect();
+
+    // Fit the stroke frames into the budget, besides the lead-in and seal frames.
+    let frame_delay_ms = if speed < 1. {
+        (frame_delay_ms as f64 / speed).round() as i32
+    } else {
+        frame_delay_ms
+    };
+    let lead_in_frames = usize::from(timing.lead_in_ms > 0);
+    let seal_frames = usize::from(seal.as_ref().is_some_and(|seal| seal.animate));
+    let by_count = budget
+        .max_frames
+        .map(|max| max.saturating_sub(lead_in_frames + seal_frames));
+    let by_duration = budget.max_duration_ms.map(|max| {
+        let fixed = i64::from(timing.lead_in_ms)
+            + i64::from(timing.final_hold_ms)
+            + pauses.iter().map(|&pause| i64::from(pause)).sum::<i64>();
+        let frames = (i64::from(max) - fixed).max(0) / i64::from(frame_delay_ms.max(1));
+        (frames as usize).saturating_sub(seal_frames)
+    });
+    let allowance = by_count.into_iter().chain(by_duration).min();
+
+    let plan = AnimationPlan {
+        paper: main_canvas,
+        steps: drawn_steps,
+        pauses,
+        lengths,
+        frame_delay_ms,
+        lead_in_ms: i32::from(timing.lead_in_ms),
+        final_hold_ms: i32::from(timing.final_hold_ms),
+        seal: seal.filter(|seal| seal.animate),
+        fan_mask,
+    };
+    let kept = kept_frame_counts(&plan.lengths, speed, allowance)?;
+
+    Ok(PreparedAnimation {
+        plan,
+        kept,
+        speed,
+        allowance,
+        layer_count: steps.iter().map(Vec::len).sum(),
+        substitutions,
+        mappings,
+    })
+}
 
-    let total_word_count = (req.word_list.len() + req.subject_list.len()) as f64;
+// Progress of a task, updated as each layer is drawn.
+fn task_progress<'a>(
+    tree: &'a Keyspace,
+    task_id: &'a str,
+    layer_count: usize,
+) -> impl FnMut() -> Result<(), AppError> + 'a {
+    let total_word_count = layer_count as f64;
     let mut cursor = 0_f64;
     let mut hashset = HashSet::with_capacity(4);
+    move || {
+        cursor += 1.;
+        check_update(
+            &mut hashset,
+            tree,
+            task_id,
+            ((cursor / total_word_count) * 100.) as usize,
+        )
+    }
+}
 
-    if req.word_list.len() >= content_strokes.len() {
-        for (idx, layer) in req.word_list.iter().enumerate() {
-            cursor += 1.;
-            check_update(
-                &mut hashset,
-                tree,
-                &req.task_id,
-                ((cursor / total_word_count) * 100.) as usize,
-            )?;
-
-            // Process word with valid frames only.
-            let strokes_opt =
-                content_strokes.get_mut(&(font_type, req.content.chars().nth(idx).unwrap()));
-            if let Some(strokes) = strokes_opt {
-                for frame in strokes.iter_mut() {
-                    frame.resize_img_by_size(layer.width, layer.height);
-                    // Apply strokes onto the main canvas
-                    imageops::overlay(
-                        &mut main_canvas,
-                        &frame.img,
-                        (layer.pos_x + layer.modify_x) as i64,
-                        layer.pos_y as i64,
-                    );
-                    // Add the word frame to encoder.
-                    encoder.add_frame(main_canvas.as_raw(), current_timestamp)?;
-                    // Advance the timestamp by the frame delay for the next frame
-                    current_timestamp += frame_delay_ms;
-                }
-            }
-        }
-        // Draw subject and signatures.
-        if req.subject_list.len() >= subject_strokes.len() {
-            for (idx, s_layer) in req.subject_list.iter().enumerate() {
-                cursor += 1.;
-                check_update(
-                    &mut hashset,
-                    tree,
-                    &req.task_id,
-                    ((cursor / total_word_count) * 100.) as usize,
-                )?;
-                let sstrokes_opt = subject_strokes
-                    .get_mut(&(sub_font_type, req.subject.chars().nth(idx).unwrap()));
-                // Process word with valid frames only.
-                if let Some(s_strokes) = sstrokes_opt {
-                    for sframe in s_strokes.iter_mut() {
-                        sframe.resize_img_by_size(s_layer.width, s_layer.height);
-                        imageops::overlay(
-                            &mut main_canvas,
-                            &sframe.img,
-                            (s_layer.pos_x + s_layer.modify_x) as i64,
-                            s_layer.pos_y as i64,
-                        );
-                        // Add the word frame to encoder.
-                        encoder.add_frame(main_canvas.as_raw(), current_timestamp)?;
-                        // Advance the timestamp by the frame delay for the next frame
-                        current_timestamp += frame_delay_ms;
-                    }
+/// An animation ready to be encoded, possibly on another thread.
+pub struct PendingAnimation {
+    prepared: PreparedAnimation,
+    task: TaskEntry,
+    format: AnimationFormat,
+    encoder_options: Option<EncoderOptions>,
+    encoding: Option<EncodingOptions>,
+}
+
+/// Prepares an animation, rendered and encoded by [`PendingAnimation::encode`].
+pub async fn prepare_poem_animation(
+    mut req: AnimationRequest,
+    tree: &Keyspace,
+) -> Result<PendingAnimation, AppError> {
+    let encoder_options = req.encoding.as_ref().map(encoder_options).transpose()?;
+    let format = req
+        .format
+        .as_deref()
+        .map_or(Ok(AnimationFormat::default()), AnimationFormat::from_str)?;
+    if format != AnimationFormat::WebP && req.encoding.is_some() {
+        return Err(AppError::InvalidEncoding(
+            "the encoding options only apply to WebP".to_string(),
+        ));
+    }
+    let settings = animation_settings(&mut req)?;
+
+    // Ensure the task id is not in used.
+    let task = TaskEntry::claim(tree, &req.task_id)?;
+    let prepared = prepare_animation(&mut req, settings).await?;
+
+    Ok(PendingAnimation {
+        prepared,
+        task,
+        format,
+        encoder_options,
+        encoding: req.encoding,
+    })
+}
+
+impl PendingAnimation {
+    /// Renders and encodes the animation, searching smaller settings when the
+    /// WebP file exceeds its target size. Blocks for as long as that takes.
+    pub fn encode(self) -> Result<RenderedAnimation, AppError> {
+        let PreparedAnimation {
+            plan,
+            kept,
+            speed,
+            allowance,
+            ..
+        } = &self.prepared;
+        let progress = self.task.progress(self.prepared.layer_count);
+
+        let mut encoding = None;
+        let (data, frame_count) = match self.format {
+            AnimationFormat::WebP => {
+                let mut encoded = encode_webp(plan, kept, self.encoder_options, progress)?;
+                // Search smaller settings when the file exceeds its target size.
+                if let Some(options) = &self.encoding
+                    && let Some(target) = options.target_size
+                {
+                    let (data, frame_count, choice) =
+                        fit_webp_to_size(plan, *speed, *allowance, options, target, encoded)?;
+                    encoded = (data, frame_count);
+                    encoding = Some(choice);
                 }
+                (encoded.0.to_vec(), encoded.1)
             }
-        }
-    } else {
-        return Err(AppError::InvalidFileName(
-            "WordList contains illegal characters".to_string(),
+            AnimationFormat::Gif => encode_gif(plan, kept, progress)?,
+            AnimationFormat::Apng => encode_apng(plan, kept, progress)?,
+        };
+
+        // Remove the task id entry as finished.
+        self.task.finish()?;
+
+        Ok(RenderedAnimation {
+            data,
+            format: self.format,
+            substitutions: self.prepared.substitutions,
+            mappings: self.prepared.mappings,
+            frame_count,
+            encoding,
+        })
+    }
+}
+
+/// Frames of an animation ready to be drawn into a zip, possibly on another thread.
+pub struct FrameSequence {
+    prepared: PreparedAnimation,
+    task: TaskEntry,
+}
+
+/// Prepares the frames of an animation, drawn as numbered PNG files by
+/// [`FrameSequence::write_zip`]. The format and encoding options do not apply.
+pub async fn prepare_poem_frames(
+    mut req: AnimationRequest,
+    tree: &Keyspace,
+) -> Result<FrameSequence, AppError> {
+    let settings = animation_settings(&mut req)?;
+
+    // Ensure the task id is not in used.
+    let task = TaskEntry::claim(tree, &req.task_id)?;
+    let prepared = prepare_animation(&mut req, settings).await?;
+
+    Ok(FrameSequence { prepared, task })
+}
+
+impl FrameSequence {
+    /// Writes the zip of the frames and their `timing.json` into the writer,
+    /// each frame as soon as it is drawn.
+    ///
+    /// Returns: frame count
+    pub fn write_zip(self, writer: impl Write) -> Result<usize, AppError> {
+        let PreparedAnimation {
+            plan,
+            kept,
+            layer_count,
+            ..
+        } = &self.prepared;
+        let mut zip = FrameZipWriter::new(writer, plan.frame_count(kept));
+        let progress = self.task.progress(*layer_count);
+        let duration = plan.render(kept, progress, |canvas, timestamp| {
+            zip.add_frame(canvas, timestamp)
+        })?;
+        let frame_count = zip.finish(duration)?;
+
+        // Remove the task id entry as finished.
+        self.task.finish()?;
+        Ok(frame_count)
+    }
+}
+
+// Refuse a glyph size that cannot be drawn, or is larger than any canvas.
+fn check_glyph_size(width: isize, height: isize) -> Result<(), AppError> {
+    if !(1..=4096).contains(&width) || !(1..=4096).contains(&height) {
+        return Err(AppError::InvalidLayout(format!(
+            "glyph dimensions must be within 1 to 4096 pixels, got {width}x{height}"
+        )));
+    }
+    Ok(())
+}
+
+/// A still image along with what was decided while drawing it.
+pub struct RenderedImage {
+    pub data: Vec<u8>,
+    pub format: StillFormat,
+    /// Words drawn in a fallback font.
+    pub substitutions: Vec<Substitution>,
+    /// Words looked up under their stored form.
+    pub mappings: Vec<CharMapping>,
+}
+
+/// Draws the finished glyph of each character of the subject at its position
+/// on the paper, then encodes the still image.
+pub async fn compose_static_image(req: DownloadRequest) -> Result<RenderedImage, AppError> {
+    if req.width <= 0 || req.height <= 0 {
+        return Err(AppError::InvalidLayout(
+            "canvas dimensions must be positive".to_string(),
+        ));
+    }
+    let format = match req.format.as_deref() {
+        Some(format) => StillFormat::from_str(format)?,
+        None => StillFormat::default(),
+    };
+    if req.transparent && format != StillFormat::Png {
+        return Err(AppError::InvalidFormat(
+            "a transparent background needs PNG output".to_string(),
         ));
     }
 
-    // Finalize the animation
-    // The last timestamp tells the encoder the total duration.
-    let webp_bytes = encoder.finalize(current_timestamp)?;
+    let font_type = CalliFont::from_str(&req.subject_font_type)?;
+    let punctuation = parse_punctuation_mode(req.punctuation.as_deref())?;
+    let mut blob_config = BlobStorageConfig::from_local_env()?;
+    blob_config.set_char_mapping(!req.exact_chars);
+    let drawn = text::drawn_chars(&req.subject, punctuation);
+    if req.subject_list.len() < drawn.len() {
+        return Err(AppError::InvalidLayout(
+            "every character needs a position in the subject list".to_string(),
+        ));
+    }
+    for layer in &req.subject_list {
+        check_glyph_size(layer.width, layer.height)?;
+    }
+    let fan = req
+        .fan
+        .as_ref()
+        .map(|options| fan_shape(options, req.width as f64, req.height as f64))
+        .transpose()?
+        .map(|(fan, _)| fan);
+
+    let content: String = drawn.iter().collect();
+    let (glyphs, substituted) = blob_config
+        .get_poem_glyphs_by_font_type(&font_type, &content)
+        .await?;
+    let mut substitutions: Vec<Substitution> = drawn
+        .iter()
+        .enumerate()
+        .filter_map(|(position, word)| {
+            let fallback = substituted.get(word)?;
+            Some(substitution("subject", position, font_type, *fallback))
+        })
+        .collect();
+    let words = drawn.iter().map(|&word| (font_type, word));
+    let mappings = char_mappings(&blob_config, "subject", words).await?;
+
+    let (canvas_width, canvas_height) = (req.width as u32, req.height as u32);
+    let seal = match &req.seal {
+        Some(options) => {
+            let placed: Vec<(f64, f64, f64, f64)> = req
+                .subject_list
+                .iter()
+                .take(drawn.len())
+                .map(|l| (l.pos_x, l.pos_y, l.width as f64, l.height as f64))
+                .collect();
+            let mut seal = Seal::from_options(
+                options,
+                &blob_config,
+                (canvas_width, canvas_height),
+                &placed,
+            )
+            .await?;
+            substitutions.append(&mut seal.substitutions);
+            Some(seal)
+        }
+        None => None,
+    };
 
-    // Remove the task id entry as finished.
-    tree.remove(&req.task_id)?;
+    let mut canvas = RgbaImage::from_pixel(canvas_width, canvas_height, Rgba([255, 255, 255, 255]));
+    // The seal lies under the ink unless it is stamped once the writing is done.
+    if let Some(seal) = seal.as_ref().filter(|seal| !seal.animate) {
+        seal.stamp(&mut canvas);
+    }
+    for (word, layer) in drawn.iter().zip(req.subject_list) {
+        let Some(glyph) = glyphs.get(&(font_type, *word)).filter(|g| !g.is_empty()) else {
+            continue;
+        };
+        let layer = AnimateSubject::from(layer);
+        let img = imageops::resize(
+            &glyph.img,
+            layer.width as u32,
+            layer.height as u32,
+            FilterType::Gaussian,
+        );
+        overlay_layer(&mut canvas, &img, &layer);
+    }
+    if let Some(seal) = seal.as_ref().filter(|seal| seal.animate) {
+        seal.stamp(&mut canvas);
+    }
+    if let Some(fan) = &fan {
+        FanMask::new(fan, canvas_width, canvas_height).apply(&mut canvas);
+    }
+    if req.transparent {
+        clear_paper(&mut canvas);
+    }
 
-    Ok(webp_bytes)
+    Ok(RenderedImage {
+        data: format.encode(&canvas)?,
+        format,
+        substitutions,
+        mappings,
+    })
 }
 
 /*
@@ -508,48 +2576,6 @@ pub fn encode_frames_to_webp(
 }
 */
 
-/// Example canvas frame filename: frame_001.png
-pub fn zip_frames_to_memory(frames: Vec<RgbaImage>) -> Result<Vec<u8>, AppError> {
-    // Create a buffer to hold the final ZIP file in memory
-    let mut zip_buffer = Vec::new();
-
-    // Use a scope to ensure the borrow of zip_buffer ends when we need to return it
-    {
-        let mut zip_writer = ZipWriter::new(Cursor::new(&mut zip_buffer));
-
-        // OPTIMIZATION: Use "Stored" (No Compression) for the ZIP container.
-        // Why? PNGs are already compressed. Compressing them again (Deflate)
-        // wastes CPU for almost zero size gain.
-        let options = SimpleFileOptions::default()
-            .compression_method(CompressionMethod::Stored)
-            .unix_permissions(0o755);
-
-        for (index, frame) in frames.iter().enumerate() {
-            let filename = format!("frame_{index:03}.png"); // e.g., frame_001.png
-
-            // Start a file entry in the ZIP
-            zip_writer.start_file(&filename, options)?;
-
-            // Encode the pixels to PNG and write directly into the Zip stream
-            // This is the memory-saving trick. We don't create a Vec<u8> for the PNG first.
-            let (width, height) = frame.dimensions();
-            let encoder = PngEncoder::new(&mut zip_writer);
-
-            encoder.write_image(
-                frame.as_raw(), // The raw pixel bytes
-                width,
-                height,
-                ExtendedColorType::Rgba8, // IMPORTANT: Tell PNG this is 8-bit RgbA
-            )?;
-        }
-
-        // Finish the ZIP structure
-        zip_writer.finish()?;
-    }
-
-    Ok(zip_buffer)
-}
-
 pub struct WordFrame {
     pub name: char,
     pub img: RgbaImage,
@@ -678,8 +2704,433 @@ impl WordFrame {
         self.img = new_img;
     }
 
+    /// Bounding box (x, y, width, height) of the ink, the dark or opaque pixels,
+    /// `None` for a blank drawing.
+    pub fn ink_bounds(&self) -> Option<PixelBox> {
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+        for (x, y, pixel) in self.img.enumerate_pixels() {
+            let [r, g, b, a] = pixel.0;
+            let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+            if a as u32 * (255 - luma) / 255 >= INK_THRESHOLD {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x);
+                bottom = bottom.max(y);
+            }
+        }
+
+        (left <= right).then(|| (left, top, right - left + 1, bottom - top + 1))
+    }
+
+    pub fn crop(&mut self, (x, y, width, height): PixelBox) {
+        let new_img = imageops::crop_imm(&self.img, x, y, width, height).to_image();
+
+        self.width = new_img.width();
+        self.height = new_img.height();
+        self.img = new_img;
+    }
+
+    // Placeholder for a word without any drawing in storage.
+    pub fn empty(name: char) -> Self {
+        Self {
+            name,
+            img: RgbaImage::new(0, 0),
+            width: 0,
+            height: 0,
+            pos_x: 0,
+            pos_y: 0,
+        }
+    }
+
     // Return `true` if the word frame is empty.
     pub fn is_empty(&self) -> bool {
         self.width == self.height && self.width == 0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KEY;
+    use fjall::{Database, KeyspaceCreateOptions};
+
+    fn temporary_tree(name: &str) -> Keyspace {
+        let path = std::env::temp_dir().join(format!("ecalli-{name}-{}", std::process::id()));
+        let db = Database::builder(path).temporary(true).open().unwrap();
+        db.keyspace(KEY, KeyspaceCreateOptions::default).unwrap()
+    }
+
+    #[test]
+    fn task_entry_frees_its_id_when_dropped() {
+        let tree = temporary_tree("task-entry");
+        let task = TaskEntry::claim(&tree, "task").unwrap();
+        assert!(matches!(
+            TaskEntry::claim(&tree, "task"),
+            Err(AppError::TaskIdInUse)
+        ));
+
+        // A task failing half way drops its entry.
+        drop(task);
+        assert!(!tree.contains_key("task").unwrap());
+        TaskEntry::claim(&tree, "task").unwrap().finish().unwrap();
+        assert!(!tree.contains_key("task").unwrap());
+    }
+
+    #[test]
+    fn fan_mask_clips_ink_past_the_paper() {
+        let fan = layout::fit_fan(200., 100., 120., 0.4);
+        let mut canvas = RgbaImage::from_pixel(200, 100, Rgba([0, 0, 0, 255]));
+        FanMask::new(&fan, 200, 100).apply(&mut canvas);
+
+        // The bottom corners lie outside the sweep, the top middle on the paper.
+        assert_eq!(canvas.get_pixel(0, 99).0[3], 0);
+        assert_eq!(canvas.get_pixel(199, 99).0[3], 0);
+        assert_eq!(*canvas.get_pixel(100, 5), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn trim_frames_crops_to_the_ink_of_the_final_frame() {
+        let mut img = RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 255]));
+        for (x, y) in [(2, 3), (6, 4)] {
+            img.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+        }
+        let frame = |img: RgbaImage| WordFrame {
+            name: '一',
+            width: img.width(),
+            height: img.height(),
+            img,
+            pos_x: 0,
+            pos_y: 0,
+        };
+        let mut frames = [frame(RgbaImage::new(10, 10)), frame(img)];
+        assert_eq!(frames[1].ink_bounds(), Some((2, 3, 5, 2)));
+
+        let config = |container: &str| BlobStorageConfig {
+            account: "account".to_string(),
+            access_key: String::new(),
+            container: container.to_string(),
+            fallbacks: HashMap::new(),
+            map_chars: true,
+            forms: Mutex::default(),
+        };
+        let drawing = config("trimmed").drawing_key("test/一.zip");
+        trim_frames(&drawing, &mut frames);
+        assert!(frames.iter().all(|f| (f.width, f.height) == (5, 2)));
+        assert_eq!(INK_BOUNDS.get(&drawing), Some(Some((2, 3, 5, 2))));
+        // The same blob in another container has its own ink.
+        let elsewhere = config("elsewhere").drawing_key("test/一.zip");
+        assert_eq!(INK_BOUNDS.get(&elsewhere), None);
+    }
+
+    fn layer(pos_x: f64, pos_y: f64) -> AnimateSubject {
+        AnimateSubject {
+            pos_x,
+            pos_y,
+            width: 10,
+            height: 10,
+            modify_x: 0.,
+            rotation: 0.,
+            font_type: None,
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn layer_words_take_the_font_and_variant_of_their_layer() {
+        let mut own_font = layer(0., 0.);
+        own_font.font_type = Some("行書".to_string());
+        own_font.variant = Some(2);
+        let layers = [own_font, layer(0., 20.)];
+
+        // Dropped marks have no layer, words past the list keep the default font.
+        let words = layer_words(
+            "春，風雨",
+            &layers,
+            CalliFont::Regular,
+            PunctuationMode::Drop,
+        )
+        .unwrap();
+        assert!(
+            words
+                == [
+                    (CalliFont::SemiCursive, '春', Some(2)),
+                    (CalliFont::Regular, '風', None),
+                    (CalliFont::Regular, '雨', None),
+                ]
+        );
+
+        let mut unknown = layer(0., 0.);
+        unknown.font_type = Some("宋體".to_string());
+        assert!(matches!(
+            layer_words("春", &[unknown], CalliFont::Regular, PunctuationMode::Drop),
+            Err(AppError::InvalidFontType(_))
+        ));
+    }
+
+    #[test]
+    fn seeded_variants_differ_across_repetitions() {
+        let variants: Vec<u32> = (0..3)
+            .map(|occurrence| seeded_variant(7, CalliFont::Seal, '永', occurrence, 3))
+            .collect();
+        let mut sorted = variants.clone();
+        sorted.sort();
+        assert_eq!(sorted, [0, 1, 2]);
+        // The same seed always picks the same variants.
+        assert_eq!(seeded_variant(7, CalliFont::Seal, '永', 0, 3), variants[0]);
+        assert_eq!(seeded_variant(7, CalliFont::Seal, '永', 5, 1), 0);
+    }
+
+    #[test]
+    fn glyphs_are_classified_as_covered_substituted_or_missing() {
+        use GlyphSource::{Animated, Missing, Static};
+        let sources = HashMap::from([
+            (CalliFont::Regular, Animated),
+            (CalliFont::SemiCursive, Static),
+            (CalliFont::Cursive, Missing),
+            (CalliFont::Clerical, Missing),
+        ]);
+        let classify = |font, fallbacks: &[CalliFont]| classify_glyph(font, fallbacks, &sources);
+
+        // A font drawing the word needs no fallback.
+        assert!(classify(CalliFont::Regular, &[CalliFont::SemiCursive]) == (Animated, None));
+        assert!(classify(CalliFont::SemiCursive, &[]) == (Static, None));
+        // The first fallback drawing it is reported, whether animated or static.
+        assert!(
+            classify(
+                CalliFont::Cursive,
+                &[
+                    CalliFont::Clerical,
+                    CalliFont::SemiCursive,
+                    CalliFont::Regular
+                ]
+            ) == (Missing, Some(CalliFont::SemiCursive))
+        );
+        // Fonts not looked at count as missing.
+        assert!(classify(CalliFont::Cursive, &[CalliFont::Seal]) == (Missing, None));
+        assert!(classify(CalliFont::Seal, &[CalliFont::Clerical]) == (Missing, None));
+    }
+
+    #[test]
+    fn pauses_tell_columns_apart() {
+        let timing = TimingOptions {
+            char_pause_ms: 100,
+            column_pause_ms: 400,
+            ..Default::default()
+        };
+        let mode = WritingMode::VerticalRtl;
+        let (top, below, next_column) = (layer(20., 0.), layer(20., 12.), layer(0., 0.));
+        assert_eq!(pause_before(&timing, None, &top, mode), 0);
+        assert_eq!(pause_before(&timing, Some(&top), &below, mode), 100);
+        assert_eq!(pause_before(&timing, Some(&below), &next_column, mode), 400);
+    }
+
+    #[test]
+    fn drawing_steps_follow_the_order() {
+        use DrawnLayer::{Subject, Word};
+        // Two columns of one word each, the right one read first, and a subject.
+        let words = [layer(0., 0.), layer(20., 0.)];
+        let subjects = [layer(40., 0.)];
+        let mode = Some(WritingMode::VerticalRtl);
+        let steps = |order, sequence| drawing_steps(order, &words, &subjects, mode, sequence);
+
+        let sequential = steps(AnimationOrder::Sequential, None).unwrap();
+        assert_eq!(sequential, [[Word(1)], [Word(0)], [Subject(0)]]);
+        let reverse = steps(AnimationOrder::Reverse, None).unwrap();
+        assert_eq!(reverse, [[Subject(0)], [Word(0)], [Word(1)]]);
+        let together = steps(AnimationOrder::Simultaneous, None).unwrap();
+        assert_eq!(together, [[Word(1), Word(0), Subject(0)]]);
+        let explicit = steps(AnimationOrder::Explicit, Some(&[2, 0])).unwrap();
+        assert_eq!(explicit, [[Subject(0)], [Word(0)]]);
+        assert!(matches!(
+            steps(AnimationOrder::Explicit, Some(&[3])),
+            Err(AppError::InvalidOrder(_))
+        ));
+    }
+
+    #[test]
+    fn kept_frames_meet_the_allowance() {
+        let lengths = [1, 1, 1, 1, 1, 1, 1, 1, 1, 100];
+        let kept = kept_frame_counts(&lengths, 1., Some(18)).unwrap();
+        assert_eq!(kept.iter().sum::<usize>(), 18);
+        assert!(kept.iter().all(|&count| count >= 1));
+
+        let kept = kept_frame_counts(&[40, 0, 20], 1., Some(30)).unwrap();
+        assert_eq!(kept, [19, 0, 10]);
+        assert_eq!(kept_frame_counts(&[40, 20], 1., None).unwrap(), [40, 20]);
+    }
+
+    #[test]
+    fn kept_frames_follow_the_speed() {
+        assert_eq!(kept_frame_counts(&[10, 3], 2., None).unwrap(), [5, 2]);
+        // Slower writing shows the frames longer instead of adding any.
+        assert_eq!(kept_frame_counts(&[10, 3], 0.5, None).unwrap(), [10, 3]);
+    }
+
+    #[test]
+    fn kept_frames_refuse_less_than_a_frame_a_step() {
+        assert!(matches!(
+            kept_frame_counts(&[5, 5, 5], 1., Some(2)),
+            Err(AppError::InvalidBudget(_))
+        ));
+        assert_eq!(
+            kept_frame_counts(&[5, 5, 5], 1., Some(3)).unwrap(),
+            [1, 1, 1]
+        );
+    }
+
+    // Steps of a single word each, the frames of a word inking one more pixel
+    // in turn so that every frame differs.
+    fn stroke_plan(lengths: &[usize]) -> AnimationPlan {
+        let steps = lengths
+            .iter()
+            .map(|&length| {
+                let frames = (0..length)
+                    .map(|n| {
+                        let mut img = RgbaImage::from_pixel(16, 16, Rgba([255, 255, 255, 255]));
+                        for pixel in img.pixels_mut().take(n + 1) {
+                            *pixel = Rgba([0, 0, 0, 255]);
+                        }
+                        img
+                    })
+                    .collect();
+                let mut word = layer(0., 0.);
+                (word.width, word.height) = (16, 16);
+                (1, vec![(word, Arc::new(frames))])
+            })
+            .collect();
+
+        AnimationPlan {
+            paper: RgbaImage::from_pixel(16, 16, Rgba([255, 255, 255, 255])),
+            steps,
+            pauses: vec![0; lengths.len()],
+            lengths: lengths.to_vec(),
+            frame_delay_ms: 40,
+            lead_in_ms: 0,
+            final_hold_ms: 0,
+            seal: None,
+            fan_mask: None,
+        }
+    }
+
+    #[test]
+    fn slowest_speed_renders_without_overflow() {
+        let mut req: AnimationRequest = serde_json::from_value(serde_json::json!({
+            "taskId": "task", "subject": "", "subjectFontType": "楷書", "subjectList": [],
+            "content": "", "fontType": "楷書", "wordList": [],
+            "width": 16, "height": 16, "fps": 25,
+            "budget": { "speed": 1e-9 },
+        }))
+        .unwrap();
+        let settings = animation_settings(&mut req).unwrap();
+        assert_eq!(settings.speed, MIN_SPEED);
+
+        let mut plan = stroke_plan(&[100; 10]);
+        plan.frame_delay_ms = (40. / settings.speed).round() as i32;
+        let duration = plan.render(&[100; 10], || Ok(()), |_, _| Ok(())).unwrap();
+        assert_eq!(duration, 1000 * 400);
+
+        // A delay past what the timestamps hold is refused instead of wrapping.
+        plan.frame_delay_ms = i32::MAX;
+        assert!(matches!(
+            plan.render(&[100; 10], || Ok(()), |_, _| Ok(())),
+            Err(AppError::InvalidBudget(_))
+        ));
+    }
+
+    #[test]
+    fn key_frame_intervals_map_to_a_valid_pair() {
+        let interval = |key_frame_interval| {
+            encoder_options(&EncodingOptions {
+                key_frame_interval,
+                ..Default::default()
+            })
+            .map(|options| (options.kmin, options.kmax))
+        };
+        assert_eq!(interval(None).unwrap(), (0, 0));
+        assert_eq!(interval(Some(1)).unwrap(), (0, 1));
+        assert!(matches!(
+            interval(Some(2)),
+            Err(AppError::InvalidEncoding(_))
+        ));
+        for kmax in 3..40 {
+            let (kmin, max) = interval(Some(kmax as u32)).unwrap();
+            assert_eq!(max, kmax);
+            assert!(kmin > kmax / 2 && kmin < kmax);
+        }
+    }
+
+    #[test]
+    fn encoder_options_refuse_out_of_range_settings() {
+        let quality = EncodingOptions {
+            quality: Some(101.),
+            ..Default::default()
+        };
+        assert!(matches!(
+            encoder_options(&quality),
+            Err(AppError::InvalidEncoding(_))
+        ));
+        let method = EncodingOptions {
+            method: Some(7),
+            ..Default::default()
+        };
+        assert!(matches!(
+            encoder_options(&method),
+            Err(AppError::InvalidEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn halved_allowance_stops_at_one_frame_a_step() {
+        let lengths = [1, 1, 1, 1, 1, 1, 1, 1, 1, 100];
+        let mut allowance = None;
+        let mut frames: Vec<usize> = Vec::new();
+        while let Some(halved) = halved_allowance(&lengths, 1., allowance).unwrap() {
+            allowance = Some(halved);
+            frames.push(
+                kept_frame_counts(&lengths, 1., allowance)
+                    .unwrap()
+                    .iter()
+                    .sum(),
+            );
+        }
+        assert_eq!(frames, [54, 27, 13, 10]);
+    }
+
+    #[test]
+    fn fit_webp_to_size_gives_up_on_an_unreachable_target() {
+        let plan = stroke_plan(&[1, 1, 1, 1, 1, 1, 1, 1, 1, 100]);
+        let kept = kept_frame_counts(&plan.lengths, 1., None).unwrap();
+        let encoded = encode_webp(&plan, &kept, None, || Ok(())).unwrap();
+        let options = EncodingOptions::default();
+
+        let fitted = fit_webp_to_size(&plan, 1., None, &options, 1, encoded);
+        assert!(matches!(fitted, Err(AppError::InvalidEncoding(_))));
+    }
+
+    #[test]
+    fn fit_webp_to_size_thins_out_frames_to_meet_the_target() {
+        let plan = stroke_plan(&[60, 60]);
+        let encode = |kept: &[usize]| encode_webp(&plan, kept, None, || Ok(())).unwrap();
+        let target = encode(&[1, 1]).0.len() * 4;
+        let encoded = encode(&kept_frame_counts(&plan.lengths, 1., None).unwrap());
+        assert!(encoded.0.len() > target);
+
+        let options = EncodingOptions::default();
+        let (data, frame_count, choice) =
+            fit_webp_to_size(&plan, 1., None, &options, target, encoded).unwrap();
+        assert!(data.len() <= target);
+        assert!(frame_count < 120);
+        assert_eq!((choice.size, choice.frame_count), (data.len(), frame_count));
+    }
+
+    #[test]
+    fn glyph_sizes_must_fit_a_canvas() {
+        assert!(check_glyph_size(1, 4096).is_ok());
+        for (width, height) in [(0, 10), (10, -3), (4097, 10), (10, 5000)] {
+            assert!(matches!(
+                check_glyph_size(width, height),
+                Err(AppError::InvalidLayout(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use std::borrow::Cow;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use gif::{DisposalMethod, Encoder as GifEncoder, Frame, Repeat};
+use image::{
+    ExtendedColorType, ImageEncoder, Rgba, RgbaImage,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops,
+};
+use png::{BitDepth, BlendOp, ColorType, DisposeOp};
+use zip::{
+    CompressionMethod, ZipWriter,
+    write::{SimpleFileOptions, StreamWriter},
+};
+
+use super::AppError;
+use super::json::{FrameSequenceTiming, FrameTiming};
+use super::seal::VERMILION;
+
+/// Container format of an animation.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnimationFormat {
+    #[default]
+    WebP,
+    Gif,
+    /// Animated PNG, lossless.
+    Apng,
+}
+
+impl FromStr for AnimationFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webp" => Ok(AnimationFormat::WebP),
+            "gif" => Ok(AnimationFormat::Gif),
+            "apng" => Ok(AnimationFormat::Apng),
+            _ => Err(AppError::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+impl AnimationFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AnimationFormat::WebP => "image/webp",
+            AnimationFormat::Gif => "image/gif",
+            AnimationFormat::Apng => "image/apng",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimationFormat::WebP => "webp",
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Apng => "png",
+        }
+    }
+}
+
+/// File format of a still image.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum StillFormat {
+    #[default]
+    Png,
+    /// Opaque only, the paper stays white.
+    Jpeg,
+}
+
+impl FromStr for StillFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(StillFormat::Png),
+            "jpeg" | "jpg" => Ok(StillFormat::Jpeg),
+            _ => Err(AppError::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+impl StillFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StillFormat::Png => "image/png",
+            StillFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StillFormat::Png => "png",
+            StillFormat::Jpeg => "jpg",
+        }
+    }
+
+    /// Encode the image, dropping its alpha channel for JPEG.
+    pub fn encode(&self, img: &RgbaImage) -> Result<Vec<u8>, AppError> {
+        let mut data = Vec::new();
+        match self {
+            StillFormat::Png => PngEncoder::new(&mut data).write_image(
+                img.as_raw(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::Rgba8,
+            )?,
+            StillFormat::Jpeg => {
+                let rgb = image::DynamicImage::ImageRgba8(img.clone()).into_rgb8();
+                JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).write_image(
+                    rgb.as_raw(),
+                    rgb.width(),
+                    rgb.height(),
+                    ExtendedColorType::Rgb8,
+                )?
+            }
+        }
+        Ok(data)
+    }
+}
+
+// Quality of JPEG stills, high enough to keep the brush edges clean.
+const JPEG_QUALITY: u8 = 92;
+
+/// Lift the white paper off a drawing, turning each pixel into the least
+/// opaque colour that gives it back when laid over white.
+pub fn clear_paper(img: &mut RgbaImage) {
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let lightest = r.min(g).min(b);
+        let alpha = 255 - lightest as u32;
+        if alpha == 0 {
+            *pixel = Rgba([255, 255, 255, 0]);
+            continue;
+        }
+        // Solve c = colour * alpha + 255 * (1 - alpha) for each channel.
+        let unmix = |c: u8| ((c as u32 + alpha - 255) * 255 / alpha) as u8;
+        *pixel = Rgba([unmix(r), unmix(g), unmix(b), (alpha * a as u32 / 255) as u8]);
+    }
+}
+
+type PixelRegion = (u32, u32, u32, u32);
+
+// Region (left, top, right, bottom) of the pixels differing between two
+// frames, `None` when they are the same.
+fn changed_region<T: PartialEq>(shown: &[T], current: &[T], width: u32) -> Option<PixelRegion> {
+    let width = width as usize;
+    let mut region: Option<PixelRegion> = None;
+    for (idx, _) in current
+        .iter()
+        .zip(shown)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+    {
+        let (x, y) = ((idx % width) as u32, (idx / width) as u32);
+        region = Some(match region {
+            None => (x, y, x + 1, y + 1),
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1))
+            }
+        });
+    }
+    region
+}
+
+// Palette index left transparent, showing the previous frame or the background.
+const TRANSPARENT: u8 = 0;
+// Greys from black to white for the ink.
+const GREYS: usize = 160;
+// Tints from the white paper to vermilion, then shades from vermilion to black, for the seal.
+const TINTS: usize = 64;
+const SHADES: usize = 31;
+
+const WHITE: [f32; 3] = [255., 255., 255.];
+const BLACK: [f32; 3] = [0., 0., 0.];
+const RED: [f32; 3] = [
+    VERMILION.0[0] as f32,
+    VERMILION.0[1] as f32,
+    VERMILION.0[2] as f32,
+];
+
+// Ramps of the seal: first colour, last colour, first index and number of entries.
+const SEAL_RAMPS: [([f32; 3], [f32; 3], usize, usize); 2] = [
+    (WHITE, RED, 1 + GREYS, TINTS),
+    (RED, BLACK, 1 + GREYS + TINTS, SHADES),
+];
+
+fn lerp(start: [f32; 3], end: [f32; 3], t: f32) -> [f32; 3] {
+    [0, 1, 2].map(|c| start[c] + (end[c] - start[c]) * t)
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
+
+/// Global palette of the GIF output as RGB triples, tuned for black ink and
+/// vermilion seals on white paper.
+pub static INK_PALETTE: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    let mut palette = vec![255, 255, 255];
+    for step in 0..GREYS {
+        let grey = (step as f32 * 255. / (GREYS - 1) as f32).round() as u8;
+        palette.extend([grey; 3]);
+    }
+    for (start, end, _, count) in SEAL_RAMPS {
+        for step in 0..count {
+            let colour = lerp(start, end, step as f32 / (count - 1) as f32);
+            palette.extend(colour.map(|c| c.round() as u8));
+        }
+    }
+    palette
+});
+
+// Index of the palette entry closest to the pixel, the transparent one for a
+// mostly transparent pixel.
+fn palette_index(pixel: &Rgba<u8>) -> u8 {
+    let [r, g, b, a] = pixel.0;
+    if a < 128 {
+        return TRANSPARENT;
+    }
+    let scale = (GREYS - 1) as f32 / 255.;
+    // Most of the paper and ink is plain grey.
+    if r == g && g == b {
+        return 1 + (r as f32 * scale).round() as u8;
+    }
+
+    let colour = [r as f32, g as f32, b as f32];
+    let luma = 0.299 * colour[0] + 0.587 * colour[1] + 0.114 * colour[2];
+    let grey_step = (luma * scale).round();
+    let grey = grey_step / scale;
+    let mut best = (distance(colour, [grey; 3]), 1 + grey_step as usize);
+    for (start, end, first, count) in SEAL_RAMPS {
+        // Nearest point of the ramp, snapped to one of its entries.
+        let axis = [0, 1, 2].map(|c| end[c] - start[c]);
+        let offset = [0, 1, 2].map(|c| colour[c] - start[c]);
+        let t = (0..3).map(|c| axis[c] * offset[c]).sum::<f32>() / distance(start, end);
+        let step = (t.clamp(0., 1.) * (count - 1) as f32).round();
+        let candidate = lerp(start, end, step / (count - 1) as f32);
+        let candidate_distance = distance(colour, candidate);
+        if candidate_distance < best.0 {
+            best = (candidate_distance, first + step as usize);
+        }
+    }
+    best.1 as u8
+}
+
+/// Writes rendered frames into an animated GIF with the ink palette.
+///
+/// A frame is written once the timestamp of the next one gives its delay,
+/// holding only the region changed since the previous frame. GIF delays are
+/// in hundredths of a second: frames rounding to the timestamp of the next
+/// one are merged into it.
+pub struct GifWriter {
+    encoder: GifEncoder<Vec<u8>>,
+    width: u32,
+    height: u32,
+    // Indexed pixels shown after the last written frame, none before the first one.
+    shown: Option<Vec<u8>>,
+    // Indexed pixels and timestamp in hundredths of a second of the frame waiting for its delay.
+    pending: Option<(Vec<u8>, u32)>,
+    frame_count: usize,
+}
+
+impl GifWriter {
+    pub fn new(width: u32, height: u32) -> Result<Self, AppError> {
+        let mut encoder = GifEncoder::new(Vec::new(), width as u16, height as u16, &INK_PALETTE)?;
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        Ok(Self {
+            encoder,
+            width,
+            height,
+            shown: None,
+            pending: None,
+            frame_count: 0,
+        })
+    }
+
+    /// Adds a frame presented at the timestamp in milliseconds.
+    pub fn add_frame(&mut self, canvas: &RgbaImage, timestamp_ms: i32) -> Result<(), AppError> {
+        let indexed: Vec<u8> = canvas.pixels().map(palette_index).collect();
+        let timestamp = centiseconds(timestamp_ms);
+        self.pending = match self.pending.take() {
+            Some((pixels, pending_timestamp)) if pending_timestamp < timestamp => {
+                self.write_frame(pixels, timestamp - pending_timestamp)?;
+                Some((indexed, timestamp))
+            }
+            // Merged into the pending frame, keeping its timestamp.
+            Some((_, pending_timestamp)) => Some((indexed, pending_timestamp)),
+            None => Some((indexed, timestamp)),
+        };
+        Ok(())
+    }
+
+    /// Writes the last frame, shown until the end of the animation.
+    ///
+    /// Returns: (GIF data, frame count)
+    pub fn finish(mut self, duration_ms: i32) -> Result<(Vec<u8>, usize), AppError> {
+        if let Some((pixels, timestamp)) = self.pending.take() {
+            let delay = centiseconds(duration_ms).saturating_sub(timestamp).max(1);
+            self.write_frame(pixels, delay)?;
+        }
+        Ok((self.encoder.into_inner()?, self.frame_count))
+    }
+
+    fn write_frame(&mut self, pixels: Vec<u8>, delay: u32) -> Result<(), AppError> {
+        let width = self.width as usize;
+        let (left, top, right, bottom, buffer) = match &self.shown {
+            None => (0, 0, self.width, self.height, pixels.clone()),
+            Some(shown) => match changed_region(shown, &pixels, self.width) {
+                // A single unchanged pixel when none changed.
+                None => (0, 0, 1, 1, vec![TRANSPARENT]),
+                Some((left, top, right, bottom)) => {
+                    // Unchanged pixels are transparent, keeping the previous frame.
+                    let buffer = (top..bottom)
+                        .flat_map(|y| (left..right).map(move |x| (y as usize) * width + x as usize))
+                        .map(|idx| {
+                            if pixels[idx] == shown[idx] {
+                                TRANSPARENT
+                            } else {
+                                pixels[idx]
+                            }
+                        })
+                        .collect();
+                    (left, top, right, bottom, buffer)
+                }
+            },
+        };
+
+        let frame = Frame {
+            delay: delay.min(u16::MAX as u32) as u16,
+            dispose: DisposalMethod::Keep,
+            transparent: Some(TRANSPARENT),
+            left: left as u16,
+            top: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+            buffer: Cow::Owned(buffer),
+            ..Default::default()
+        };
+        self.encoder.write_frame(&frame)?;
+        self.shown = Some(pixels);
+        self.frame_count += 1;
+        Ok(())
+    }
+}
+
+/// Writes rendered frames into an animated PNG, losslessly.
+///
+/// APNG announces its number of frames up front. A frame is written once the
+/// timestamp of the next one gives its delay, holding only the region changed
+/// since the previous frame.
+pub struct ApngWriter<'a> {
+    writer: png::Writer<&'a mut Vec<u8>>,
+    // Last written frame.
+    shown: Option<RgbaImage>,
+    // Frame waiting for its delay, and its timestamp in milliseconds.
+    pending: Option<(RgbaImage, i32)>,
+    frame_count: usize,
+}
+
+impl<'a> ApngWriter<'a> {
+    pub fn new(
+        data: &'a mut Vec<u8>,
+        width: u32,
+        height: u32,
+        frame_count: usize,
+    ) -> Result<Self, AppError> {
+        let mut encoder = png::Encoder::new(data, width, height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        // Played forever, each frame replacing its region and staying on.
+        encoder.set_animated(frame_count as u32, 0)?;
+        encoder.set_blend_op(BlendOp::Source)?;
+        encoder.set_dispose_op(DisposeOp::None)?;
+
+        Ok(Self {
+            writer: encoder.write_header()?,
+            shown: None,
+            pending: None,
+            frame_count: 0,
+        })
+    }
+
+    /// Adds a frame presented at the timestamp in milliseconds.
+    pub fn add_frame(&mut self, canvas: &RgbaImage, timestamp_ms: i32) -> Result<(), AppError> {
+        if let Some((frame, pending_timestamp)) = self.pending.take() {
+            self.write_frame(frame, timestamp_ms - pending_timestamp)?;
+        }
+        self.pending = Some((canvas.clone(), timestamp_ms));
+        Ok(())
+    }
+
+    /// Writes the last frame, shown until the end of the animation.
+    ///
+    /// Returns: frame count
+    pub fn finish(mut self, duration_ms: i32) -> Result<usize, AppError> {
+        if let Some((frame, timestamp)) = self.pending.take() {
+            self.write_frame(frame, duration_ms - timestamp)?;
+        }
+        let frame_count = self.frame_count;
+        self.writer.finish()?;
+        Ok(frame_count)
+    }
+
+    fn write_frame(&mut self, frame: RgbaImage, delay_ms: i32) -> Result<(), AppError> {
+        let (width, height) = frame.dimensions();
+        let (left, top, right, bottom) = match &self.shown {
+            None => (0, 0, width, height),
+            Some(shown) => {
+                let (shown_pixels, _) = shown.as_raw().as_chunks::<4>();
+                let (pixels, _) = frame.as_raw().as_chunks::<4>();
+                // A single unchanged pixel when none changed.
+                changed_region(shown_pixels, pixels, width).unwrap_or((0, 0, 1, 1))
+            }
+        };
+        let region = imageops::crop_imm(&frame, left, top, right - left, bottom - top).to_image();
+
+        // The delay in milliseconds, or in hundredths of a second when too long.
+        let delay_ms = delay_ms.max(0) as u32;
+        let (numerator, denominator) = match u16::try_from(delay_ms) {
+            Ok(delay) => (delay, 1000),
+            Err(_) => (u16::try_from(delay_ms / 10).unwrap_or(u16::MAX), 100),
+        };
+        self.writer.reset_frame_position()?;
+        self.writer
+            .set_frame_dimension(region.width(), region.height())?;
+        self.writer.set_frame_position(left, top)?;
+        self.writer.set_frame_delay(numerator, denominator)?;
+        self.writer.write_image_data(region.as_raw())?;
+
+        self.shown = Some(frame);
+        self.frame_count += 1;
+        Ok(())
+    }
+}
+
+/// Writes rendered frames into a zip of numbered PNG files, along with a
+/// `timing.json` giving how long each one is shown.
+///
+/// The zip is streamed: nothing written is seeked back to.
+pub struct FrameZipWriter<W: Write> {
+    zip: ZipWriter<StreamWriter<W>>,
+    // Digits of the frame numbers, so that the file names sort in order.
+    digits: usize,
+    // File name and timestamp of each frame.
+    frames: Vec<(String, i32)>,
+}
+
+impl<W: Write> FrameZipWriter<W> {
+    pub fn new(writer: W, frame_count: usize) -> Self {
+        Self {
+            zip: ZipWriter::new_stream(writer),
+            digits: frame_count.to_string().len().max(3),
+            frames: Vec::with_capacity(frame_count),
+        }
+    }
+
+    // PNGs are already compressed, store them as they are.
+    fn file_options() -> SimpleFileOptions {
+        SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .unix_permissions(0o644)
+    }
+
+    /// Adds a frame presented at the timestamp in milliseconds, numbered from
+    /// frame_000.png.
+    pub fn add_frame(&mut self, canvas: &RgbaImage, timestamp_ms: i32) -> Result<(), AppError> {
+        let file = format!(
+            "frame_{:0digits$}.png",
+            self.frames.len(),
+            digits = self.digits
+        );
+        self.zip.start_file(file.as_str(), Self::file_options())?;
+        let (width, height) = canvas.dimensions();
+        PngEncoder::new(&mut self.zip).write_image(
+            canvas.as_raw(),
+            width,
+            height,
+            ExtendedColorType::Rgba8,
+        )?;
+        self.frames.push((file, timestamp_ms));
+        Ok(())
+    }
+
+    /// Writes `timing.json`, the last frame being shown until the end of the
+    /// animation, and ends the zip.
+    ///
+    /// Returns: frame count
+    pub fn finish(mut self, duration_ms: i32) -> Result<usize, AppError> {
+        let ends = self
+            .frames
+            .iter()
+            .skip(1)
+            .map(|(_, timestamp)| *timestamp)
+            .chain([duration_ms]);
+        let frames: Vec<FrameTiming> = self
+            .frames
+            .iter()
+            .zip(ends)
+            .map(|((file, timestamp), end)| FrameTiming {
+                file: file.clone(),
+                duration_ms: end - timestamp,
+            })
+            .collect();
+        let timing = FrameSequenceTiming {
+            frame_count: frames.len(),
+            duration_ms,
+            frames,
+        };
+
+        self.zip.start_file("timing.json", Self::file_options())?;
+        serde_json::to_writer_pretty(&mut self.zip, &timing)?;
+        let mut writer = self.zip.finish()?.into_inner();
+        writer.flush()?;
+        Ok(timing.frame_count)
+    }
+}
+
+// Timestamp in hundredths of a second, the unit of GIF delays.
+fn centiseconds(timestamp_ms: i32) -> u32 {
+    (timestamp_ms.max(0) as u32 + 5) / 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u8) -> [u8; 3] {
+        let start = index as usize * 3;
+        [0, 1, 2].map(|c| INK_PALETTE[start + c])
+    }
+
+    #[test]
+    fn palette_holds_the_paper_ink_and_seal() {
+        assert_eq!(INK_PALETTE.len(), 256 * 3);
+        assert_eq!(palette_index(&Rgba([255, 255, 255, 0])), TRANSPARENT);
+        assert_eq!(entry(palette_index(&Rgba([255, 255, 255, 255]))), [255; 3]);
+        assert_eq!(entry(palette_index(&Rgba([0, 0, 0, 255]))), [0; 3]);
+        let vermilion = palette_index(&VERMILION);
+        assert_eq!(
+            entry(vermilion),
+            [VERMILION.0[0], VERMILION.0[1], VERMILION.0[2]]
+        );
+    }
+
+    #[test]
+    fn palette_keeps_colours_close() {
+        for pixel in [
+            [128, 128, 128],
+            [240, 200, 190],
+            [120, 30, 25],
+            [90, 92, 88],
+        ] {
+            let found = entry(palette_index(&Rgba([pixel[0], pixel[1], pixel[2], 255])));
+            let error = distance(pixel.map(f32::from), found.map(f32::from)).sqrt();
+            assert!(error < 12., "{pixel:?} drawn as {found:?}");
+        }
+    }
+
+    #[test]
+    fn changed_region_bounds_the_differing_pixels() {
+        let shown = [0; 12];
+        let mut current = shown;
+        assert_eq!(changed_region(&shown, &current, 4), None);
+        current[5] = 1;
+        current[10] = 1;
+        assert_eq!(changed_region(&shown, &current, 4), Some((1, 1, 3, 3)));
+    }
+
+    #[test]
+    fn gif_frames_merge_within_a_hundredth_of_a_second() {
+        let paper = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        let mut ink = paper.clone();
+        ink.put_pixel(1, 2, Rgba([0, 0, 0, 255]));
+
+        let mut writer = GifWriter::new(4, 4).unwrap();
+        writer.add_frame(&paper, 0).unwrap();
+        writer.add_frame(&ink, 3).unwrap();
+        writer.add_frame(&paper, 40).unwrap();
+        let (data, frame_count) = writer.finish(100).unwrap();
+        assert_eq!(frame_count, 2);
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(data.as_slice()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, [4, 6]);
+    }
+
+    #[test]
+    fn apng_frames_hold_until_the_next_timestamp() {
+        let paper = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        let mut ink = paper.clone();
+        ink.put_pixel(2, 1, Rgba([0, 0, 0, 255]));
+
+        let mut data = Vec::new();
+        let mut writer = ApngWriter::new(&mut data, 4, 4, 2).unwrap();
+        writer.add_frame(&paper, 0).unwrap();
+        writer.add_frame(&ink, 250).unwrap();
+        assert_eq!(writer.finish(1000).unwrap(), 2);
+
+        let mut reader = png::Decoder::new(std::io::Cursor::new(data))
+            .read_info()
+            .unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size().unwrap()];
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            reader.next_frame(&mut buffer).unwrap();
+            let control = reader.info().frame_control().unwrap();
+            frames.push((
+                control.width,
+                control.x_offset,
+                control.y_offset,
+                control.delay_num,
+                control.delay_den,
+            ));
+        }
+        // The second frame only holds the changed pixel.
+        assert_eq!(frames, [(4, 0, 0, 250, 1000), (1, 2, 1, 750, 1000)]);
+    }
+
+    #[test]
+    fn frame_zip_numbers_frames_from_zero_and_times_them() {
+        let canvas = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        let mut data = Vec::new();
+        let mut writer = FrameZipWriter::new(&mut data, 2);
+        writer.add_frame(&canvas, 0).unwrap();
+        writer.add_frame(&canvas, 30).unwrap();
+        assert_eq!(writer.finish(100).unwrap(), 2);
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"frame_000.png") && names.contains(&"frame_001.png"));
+        let timing: serde_json::Value =
+            serde_json::from_reader(archive.by_name("timing.json").unwrap()).unwrap();
+        assert_eq!(timing["durationMs"], 100);
+        assert_eq!(timing["frames"][0]["durationMs"], 30);
+        assert_eq!(timing["frames"][1]["file"], "frame_001.png");
+        assert_eq!(timing["frames"][1]["durationMs"], 70);
+    }
+
+    #[test]
+    fn clearing_the_paper_keeps_the_ink_over_white() {
+        let mut img = RgbaImage::from_vec(
+            3,
+            1,
+            vec![255, 255, 255, 255, 0, 0, 0, 255, 255, 128, 128, 255],
+        )
+        .unwrap();
+        clear_paper(&mut img);
+        assert_eq!(img.get_pixel(0, 0)[3], 0);
+        assert_eq!(img.get_pixel(1, 0).0, [0, 0, 0, 255]);
+        // Laid back over white, the tint comes out as it was.
+        let [r, g, b, a] = img.get_pixel(2, 0).0;
+        let over_white = |c: u8| (c as u32 * a as u32 + 255 * (255 - a as u32)) / 255;
+        assert_eq!(
+            [over_white(r), over_white(g), over_white(b)],
+            [255, 128, 128]
+        );
+    }
+
+    #[test]
+    fn still_formats_encode_their_signature() {
+        let img = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 0]));
+        assert!(
+            StillFormat::Png
+                .encode(&img)
+                .unwrap()
+                .starts_with(b"\x89PNG")
+        );
+        assert!(
+            StillFormat::Jpeg
+                .encode(&img)
+                .unwrap()
+                .starts_with(&[0xFF, 0xD8])
+        );
+    }
+}
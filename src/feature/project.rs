@@ -0,0 +1,207 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fjall::Keyspace;
+use serde_json::{Map, Value};
+
+use super::AppError;
+use super::json::Project;
+
+/// Schema version written by this server.
+///
+/// Bump it along with a migration when a field is renamed, removed or changes
+/// meaning, or when older documents need a new field filled in to give the
+/// same output. A new optional field whose default changes nothing, as
+/// `timing`, `order`, `sequence` and `budget` were, needs no new version.
+///
+/// Version 2 records the output `format` and `encoding` of the piece.
+pub const PROJECT_VERSION: u32 = 2;
+
+// Upgrades a document from the version at its index to the next one.
+type Migration = fn(Map<String, Value>) -> Result<Map<String, Value>, AppError>;
+
+/// Steps upgrading a document, the n-th one from version n to n + 1.
+const MIGRATIONS: [Migration; PROJECT_VERSION as usize] = [migrate_v0, migrate_v1];
+
+// Version 0 is a bare animation request: keep what describes the piece and
+// drop the task it was rendered under.
+fn migrate_v0(mut document: Map<String, Value>) -> Result<Map<String, Value>, AppError> {
+    document.remove("taskId");
+    document
+        .entry("name")
+        .or_insert_with(|| Value::String(String::new()));
+
+    Ok(document)
+}
+
+// Version 1 has no output settings: its pieces were encoded as WebP with the
+// encoder defaults.
+fn migrate_v1(mut document: Map<String, Value>) -> Result<Map<String, Value>, AppError> {
+    document
+        .entry("format")
+        .or_insert_with(|| Value::String("webp".to_string()));
+    document.entry("encoding").or_insert(Value::Null);
+
+    Ok(document)
+}
+
+/// Upgrades a project document of any known version to the latest schema.
+///
+/// Documents without a version are taken as animation requests.
+pub fn migrate(document: Value) -> Result<Project, AppError> {
+    let Value::Object(mut document) = document else {
+        return Err(AppError::InvalidProject(
+            "the document must be a JSON object".to_string(),
+        ));
+    };
+    let version = match document.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| AppError::InvalidProject("unknown version".to_string()))?,
+    };
+    if version > PROJECT_VERSION {
+        return Err(AppError::InvalidProject(format!(
+            "version {version} is newer than the supported {PROJECT_VERSION}"
+        )));
+    }
+
+    for step in &MIGRATIONS[version as usize..] {
+        document = step(document)?;
+    }
+    document.insert("version".to_string(), Value::from(PROJECT_VERSION));
+
+    Ok(serde_json::from_value(Value::Object(document))?)
+}
+
+// Identifier of a new project, from the time of its import.
+fn new_project_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!("{nanos:x}")
+}
+
+/// Migrates the document and stores it, under the given id or a new one.
+/// A project already stored under the id is only replaced with `overwrite`.
+///
+/// Returns: (project id, migrated project)
+pub fn import_project(
+    tree: &Keyspace,
+    id: Option<String>,
+    overwrite: bool,
+    document: Value,
+) -> Result<(String, Project), AppError> {
+    let project = migrate(document)?;
+    let id = match id {
+        Some(id) if id.trim().is_empty() => {
+            return Err(AppError::InvalidProject(
+                "the project id cannot be blank".to_string(),
+            ));
+        }
+        Some(id) => id,
+        None => new_project_id(),
+    };
+    if !overwrite && tree.contains_key(id.as_str())? {
+        return Err(AppError::ProjectExists(id));
+    }
+    tree.insert(id.as_str(), serde_json::to_vec(&project)?)?;
+
+    Ok((id, project))
+}
+
+/// Loads a stored project, upgrading it when stored by an older version.
+pub fn export_project(tree: &Keyspace, id: &str) -> Result<Project, AppError> {
+    let stored = tree
+        .get(id)?
+        .ok_or_else(|| AppError::ProjectNotFound(id.to_string()))?;
+    let document: Value = serde_json::from_slice(&stored)?;
+    let outdated = document.get("version").and_then(Value::as_u64) != Some(PROJECT_VERSION as u64);
+    let project = migrate(document)?;
+    if outdated {
+        tree.insert(id, serde_json::to_vec(&project)?)?;
+    }
+
+    Ok(project)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROJECT_KEY;
+    use fjall::{Database, KeyspaceCreateOptions};
+    use serde_json::json;
+
+    fn animation_request() -> Value {
+        json!({
+            "taskId": "task",
+            "content": "春眠",
+            "fontType": "楷書",
+            "subjectFontType": "楷書",
+            "width": 400,
+            "height": 300,
+            "fps": 25,
+            "wordList": [],
+        })
+    }
+
+    #[test]
+    fn migrate_upgrades_an_animation_request() {
+        let project = migrate(animation_request()).unwrap();
+        assert_eq!(project.version, PROJECT_VERSION);
+        assert_eq!(project.name, "");
+        assert_eq!(project.content, "春眠");
+    }
+
+    #[test]
+    fn migrate_fills_in_the_output_of_version_1() {
+        let mut document = animation_request();
+        document["version"] = json!(1);
+        document["name"] = json!("春曉");
+        let project = migrate(document).unwrap();
+        assert_eq!(project.format.as_deref(), Some("webp"));
+        assert!(project.encoding.is_none());
+
+        // The output settings survive an export and import.
+        let mut document = animation_request();
+        document["format"] = json!("gif");
+        document["encoding"] = json!({ "quality": 60.0, "keyFrameInterval": 8 });
+        let exported = serde_json::to_value(migrate(document).unwrap()).unwrap();
+        let project = migrate(exported).unwrap();
+        assert_eq!(project.format.as_deref(), Some("gif"));
+        let encoding = project.encoding.unwrap();
+        assert_eq!(encoding.quality, Some(60.));
+        assert_eq!(encoding.key_frame_interval, Some(8));
+    }
+
+    #[test]
+    fn migrate_refuses_newer_and_malformed_documents() {
+        let mut newer = animation_request();
+        newer["version"] = json!(PROJECT_VERSION + 1);
+        assert!(matches!(migrate(newer), Err(AppError::InvalidProject(_))));
+        assert!(matches!(
+            migrate(json!([])),
+            Err(AppError::InvalidProject(_))
+        ));
+    }
+
+    #[test]
+    fn import_keeps_an_existing_project_unless_overwritten() {
+        let path = std::env::temp_dir().join(format!("ecalli-projects-{}", std::process::id()));
+        let db = Database::builder(path).temporary(true).open().unwrap();
+        let tree = db
+            .keyspace(PROJECT_KEY, KeyspaceCreateOptions::default)
+            .unwrap();
+        let id = Some("poem".to_string());
+
+        import_project(&tree, id.clone(), false, animation_request()).unwrap();
+        assert!(matches!(
+            import_project(&tree, id.clone(), false, animation_request()),
+            Err(AppError::ProjectExists(_))
+        ));
+        let mut renamed = animation_request();
+        renamed["name"] = json!("春曉");
+        import_project(&tree, id, true, renamed).unwrap();
+        assert_eq!(export_project(&tree, "poem").unwrap().name, "春曉");
+    }
+}
@@ -0,0 +1,370 @@
+use std::str::FromStr;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::{
+    Rgba, RgbaImage,
+    imageops::{self, FilterType},
+};
+
+use super::json::{SealOptions, Substitution};
+use super::{AppError, BlobStorageConfig, CalliFont, substitution};
+
+/// Colour of the seal paste.
+pub const VERMILION: Rgba<u8> = Rgba([227, 66, 52, 255]);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum SealShape {
+    #[default]
+    Square,
+    Round,
+}
+
+impl FromStr for SealShape {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(SealShape::Square),
+            "round" => Ok(SealShape::Round),
+            _ => Err(AppError::InvalidSeal(format!("unknown shape {s}"))),
+        }
+    }
+}
+
+/// Where the seal is stamped when no explicit position is given.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum SealAnchor {
+    #[default]
+    BottomLeft,
+    BottomRight,
+    TopLeft,
+    TopRight,
+    /// Right after the last glyph of the inscription.
+    Inscription,
+}
+
+impl FromStr for SealAnchor {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bottom-left" => Ok(SealAnchor::BottomLeft),
+            "bottom-right" => Ok(SealAnchor::BottomRight),
+            "top-left" => Ok(SealAnchor::TopLeft),
+            "top-right" => Ok(SealAnchor::TopRight),
+            "inscription" => Ok(SealAnchor::Inscription),
+            _ => Err(AppError::InvalidSeal(format!("unknown anchor {s}"))),
+        }
+    }
+}
+
+/// A seal ready to be stamped onto the canvas.
+pub struct Seal {
+    pub img: RgbaImage,
+    pub pos_x: i64,
+    pub pos_y: i64,
+    /// Stamp the seal on a final frame instead of the blank canvas.
+    pub animate: bool,
+    /// Carved characters drawn in a fallback font as the seal font has none.
+    pub substitutions: Vec<Substitution>,
+}
+
+impl Seal {
+    /// Prepare the seal described by the request options.
+    ///
+    /// `inscription` holds the placed inscription glyphs as (x, y, width, height)
+    /// in reading order, used by the inscription anchor.
+    pub async fn from_options(
+        options: &SealOptions,
+        blob_config: &BlobStorageConfig,
+        (canvas_width, canvas_height): (u32, u32),
+        inscription: &[(f64, f64, f64, f64)],
+    ) -> Result<Self, AppError> {
+        if options.size < 16 || options.size as u32 > canvas_width.min(canvas_height) {
+            return Err(AppError::InvalidSeal(
+                "size must be at least 16 pixels and fit in the canvas".to_string(),
+            ));
+        }
+        let size = options.size as u32;
+
+        let mut substitutions = Vec::new();
+        let img = match &options.image {
+            Some(encoded) => {
+                // Accept both raw base64 and data URLs.
+                let data = encoded.rsplit(',').next().unwrap_or_default();
+                let bytes = STANDARD
+                    .decode(data.trim())
+                    .map_err(|e| AppError::InvalidSeal(e.to_string()))?;
+                let uploaded = image::load_from_memory(&bytes)?;
+                uploaded.resize(size, size, FilterType::Gaussian).into()
+            }
+            None => {
+                let chars: Vec<char> = options
+                    .text
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect();
+                if chars.is_empty() || chars.len() > 4 {
+                    return Err(AppError::InvalidSeal(
+                        "text must hold one to four characters".to_string(),
+                    ));
+                }
+                let shape = options
+                    .shape
+                    .as_deref()
+                    .map_or(Ok(SealShape::default()), SealShape::from_str)?;
+                let (glyphs, substituted) = blob_config
+                    .get_poem_glyphs_by_font_type(&CalliFont::Seal, &String::from_iter(&chars))
+                    .await?;
+                substitutions = chars
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(position, c)| {
+                        let fallback = substituted.get(c)?;
+                        Some(substitution("seal", position, CalliFont::Seal, *fallback))
+                    })
+                    .collect();
+                // A character without any glyph leaves its cell empty.
+                let glyph_imgs: Vec<Option<&RgbaImage>> = chars
+                    .iter()
+                    .map(|c| glyphs.get(&(CalliFont::Seal, *c)).map(|frame| &frame.img))
+                    .collect();
+                carve_seal(&glyph_imgs, size, shape)
+            }
+        };
+
+        let (pos_x, pos_y) = match (options.pos_x, options.pos_y) {
+            (Some(x), Some(y)) => (x as i64, y as i64),
+            _ => {
+                let anchor = options
+                    .anchor
+                    .as_deref()
+                    .map_or(Ok(SealAnchor::default()), SealAnchor::from_str)?;
+                let margin = options.margin.unwrap_or(options.size / 4).max(0) as f64;
+                anchor_position(
+                    anchor,
+                    (img.width() as f64, img.height() as f64),
+                    (canvas_width as f64, canvas_height as f64),
+                    margin,
+                    inscription,
+                )
+            }
+        };
+
+        Ok(Self {
+            img,
+            pos_x,
+            pos_y,
+            animate: options.animate,
+            substitutions,
+        })
+    }
+
+    pub fn stamp(&self, canvas: &mut RgbaImage) {
+        imageops::overlay(canvas, &self.img, self.pos_x, self.pos_y);
+    }
+}
+
+// Top-left corner of the seal for the given anchor.
+fn anchor_position(
+    anchor: SealAnchor,
+    (width, height): (f64, f64),
+    (canvas_width, canvas_height): (f64, f64),
+    margin: f64,
+    inscription: &[(f64, f64, f64, f64)],
+) -> (i64, i64) {
+    let (x, y) = match (anchor, inscription.last()) {
+        (SealAnchor::TopLeft, _) => (margin, margin),
+        (SealAnchor::TopRight, _) => (canvas_width - width - margin, margin),
+        (SealAnchor::BottomRight, _) => (
+            canvas_width - width - margin,
+            canvas_height - height - margin,
+        ),
+        // Below the last glyph of a column, or after it in a row.
+        (SealAnchor::Inscription, Some(&(x, y, w, h))) if h >= w => {
+            (x + (w - width) / 2., y + h + margin)
+        }
+        (SealAnchor::Inscription, Some(&(x, y, w, h))) => (x + w + margin, y + (h - height) / 2.),
+        (SealAnchor::BottomLeft | SealAnchor::Inscription, _) => {
+            (margin, canvas_height - height - margin)
+        }
+    };
+
+    (x.round() as i64, y.round() as i64)
+}
+
+// Recolour the ink of a glyph, dark or opaque pixels, with the seal paste.
+fn tint(glyph: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+    let mut resized = imageops::resize(glyph, width, height, FilterType::Gaussian);
+    for pixel in resized.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+        let ink = a as u32 * (255 - luma) / 255;
+        *pixel = Rgba([VERMILION[0], VERMILION[1], VERMILION[2], ink as u8]);
+    }
+    resized
+}
+
+// Carve up to four seal glyphs into a bordered seal, read in right-to-left
+// columns. Missing glyphs keep their cell, left empty.
+fn carve_seal(glyphs: &[Option<&RgbaImage>], size: u32, shape: SealShape) -> RgbaImage {
+    let mut seal = RgbaImage::new(size, size);
+    let border = (size / 16).max(1);
+    let centre = size as f64 / 2.;
+
+    // Draw the border.
+    for (x, y, pixel) in seal.enumerate_pixels_mut() {
+        let on_border = match shape {
+            SealShape::Square => {
+                x < border || y < border || x >= size - border || y >= size - border
+            }
+            SealShape::Round => {
+                let distance =
+                    ((x as f64 + 0.5 - centre).powi(2) + (y as f64 + 0.5 - centre).powi(2)).sqrt();
+                distance <= centre && distance >= centre - border as f64
+            }
+        };
+        if on_border {
+            *pixel = VERMILION;
+        }
+    }
+
+    // Glyphs fill the square inside the border, or the one inscribed in the circle.
+    let inner = match shape {
+        SealShape::Square => (size - 3 * border) as f64,
+        SealShape::Round => (size as f64 - 3. * border as f64) / std::f64::consts::SQRT_2,
+    };
+    let origin = centre - inner / 2.;
+    let half = inner / 2.;
+
+    // Cells as (x, y, width, height) within the inner square, in reading order.
+    let cells: Vec<(f64, f64, f64, f64)> = match glyphs.len() {
+        1 => vec![(0., 0., inner, inner)],
+        2 => vec![(half, 0., half, inner), (0., 0., half, inner)],
+        3 => vec![
+            (half, 0., half, inner),
+            (0., 0., half, half),
+            (0., half, half, half),
+        ],
+        _ => vec![
+            (half, 0., half, half),
+            (half, half, half, half),
+            (0., 0., half, half),
+            (0., half, half, half),
+        ],
+    };
+
+    for (glyph, (cell_x, cell_y, cell_w, cell_h)) in glyphs.iter().zip(cells) {
+        let Some(glyph) = glyph.filter(|g| g.width() > 0 && g.height() > 0) else {
+            continue;
+        };
+        let tinted = tint(glyph, cell_w.max(1.) as u32, cell_h.max(1.) as u32);
+        imageops::overlay(
+            &mut seal,
+            &tinted,
+            (origin + cell_x) as i64,
+            (origin + cell_y) as i64,
+        );
+    }
+
+    seal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchors_place_the_seal_within_the_margin() {
+        let place = |anchor, inscription: &[(f64, f64, f64, f64)]| {
+            anchor_position(anchor, (20., 20.), (200., 100.), 5., inscription)
+        };
+        assert_eq!(place(SealAnchor::TopLeft, &[]), (5, 5));
+        assert_eq!(place(SealAnchor::TopRight, &[]), (175, 5));
+        assert_eq!(place(SealAnchor::BottomRight, &[]), (175, 75));
+        assert_eq!(place(SealAnchor::BottomLeft, &[]), (5, 75));
+        // Without an inscription, the seal goes to the bottom left.
+        assert_eq!(place(SealAnchor::Inscription, &[]), (5, 75));
+    }
+
+    #[test]
+    fn inscription_anchor_follows_its_last_glyph() {
+        let place = |inscription: &[(f64, f64, f64, f64)]| {
+            anchor_position(
+                SealAnchor::Inscription,
+                (20., 20.),
+                (200., 100.),
+                5.,
+                inscription,
+            )
+        };
+        // Below the last glyph of a column, centred on it.
+        assert_eq!(place(&[(0., 0., 30., 30.), (50., 10., 30., 40.)]), (55, 55));
+        // After the last glyph of a row, centred on it.
+        assert_eq!(place(&[(50., 10., 40., 30.)]), (95, 15));
+    }
+
+    #[test]
+    fn tint_turns_ink_into_vermilion() {
+        let tinted = |pixel: [u8; 4]| {
+            let glyph = RgbaImage::from_pixel(1, 1, Rgba(pixel));
+            tint(&glyph, 1, 1).get_pixel(0, 0).0
+        };
+        let [r, g, b, _] = VERMILION.0;
+        assert_eq!(tinted([0, 0, 0, 255]), [r, g, b, 255]);
+        assert_eq!(tinted([0, 0, 0, 128]), [r, g, b, 128]);
+        assert_eq!(tinted([255, 255, 255, 255])[3], 0);
+        assert_eq!(tinted([0, 0, 0, 0])[3], 0);
+    }
+
+    #[test]
+    fn seal_border_follows_its_shape() {
+        let square = carve_seal(&[], 64, SealShape::Square);
+        for (x, y) in [(0, 0), (3, 32), (63, 63), (32, 60)] {
+            assert_eq!(*square.get_pixel(x, y), VERMILION, "({x}, {y})");
+        }
+        assert_eq!(square.get_pixel(4, 32)[3], 0);
+
+        let round = carve_seal(&[], 64, SealShape::Round);
+        for (x, y) in [(32, 0), (0, 32), (32, 63)] {
+            assert_eq!(*round.get_pixel(x, y), VERMILION, "({x}, {y})");
+        }
+        // The corners lie outside of the circle.
+        for (x, y) in [(0, 0), (63, 63), (32, 32)] {
+            assert_eq!(round.get_pixel(x, y)[3], 0, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn glyphs_fill_their_cells_in_reading_order() {
+        let ink = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255]));
+        for shape in [SealShape::Square, SealShape::Round] {
+            // Offset of the quadrant centres from the seal centre.
+            let quarter = match shape {
+                SealShape::Square => 52. / 4.,
+                SealShape::Round => 52. / std::f64::consts::SQRT_2 / 4.,
+            };
+            let (right, left) = ((32. + quarter) as u32, (32. - quarter) as u32);
+            let (top, bottom) = (left, right);
+            let cells: [&[(u32, u32)]; 4] = [
+                &[(32, 32)],
+                &[(right, 32), (left, 32)],
+                &[(right, 32), (left, top), (left, bottom)],
+                &[(right, top), (right, bottom), (left, top), (left, bottom)],
+            ];
+            for centres in cells {
+                for inked in 0..centres.len() {
+                    // Only one glyph is found, the others leave their cell empty.
+                    let glyphs: Vec<Option<&RgbaImage>> = (0..centres.len())
+                        .map(|n| (n == inked).then_some(&ink))
+                        .collect();
+                    let seal = carve_seal(&glyphs, 64, shape);
+                    for (n, &(x, y)) in centres.iter().enumerate() {
+                        let alpha = seal.get_pixel(x, y)[3];
+                        assert_eq!(alpha > 0, n == inked, "cell {n} of {}", centres.len());
+                    }
+                }
+            }
+        }
+    }
+}
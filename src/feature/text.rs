@@ -0,0 +1,172 @@
+use std::str::FromStr;
+
+use super::AppError;
+
+/// Marker ending a stanza, the same as an empty line in the content.
+pub const STANZA_BREAK: char = '¶';
+
+/// How punctuation marks found in the content are handled.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum PunctuationMode {
+    /// Not drawn, the mark only leaves a short pause in the line.
+    #[default]
+    Drop,
+    /// Drawn as a small mark in the corner of its own cell.
+    Render,
+}
+
+impl FromStr for PunctuationMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(PunctuationMode::Drop),
+            "render" => Ok(PunctuationMode::Render),
+            _ => Err(AppError::InvalidPunctuationMode(s.to_string())),
+        }
+    }
+}
+
+/// A unit of the content as seen by the layout engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Word(char),
+    Punctuation(char),
+    /// Dropped punctuation, nothing is drawn.
+    Pause,
+    /// A newline, the next word starts a new line.
+    LineBreak,
+    /// Starts a new line after a blank one.
+    StanzaBreak,
+}
+
+impl Token {
+    /// The character drawn for the token, if any.
+    pub fn drawn_char(&self) -> Option<char> {
+        match self {
+            Token::Word(c) | Token::Punctuation(c) => Some(*c),
+            Token::Pause | Token::LineBreak | Token::StanzaBreak => None,
+        }
+    }
+}
+
+pub fn is_punctuation(c: char) -> bool {
+    matches!(
+        c,
+        '，' | '。'
+            | '、'
+            | '；'
+            | '：'
+            | '？'
+            | '！'
+            | '「'
+            | '」'
+            | '『'
+            | '』'
+            | '（'
+            | '）'
+            | '《'
+            | '》'
+            | '〈'
+            | '〉'
+            | '…'
+            | '—'
+            | '·'
+            | ','
+            | '.'
+            | ';'
+            | ':'
+            | '?'
+            | '!'
+    )
+}
+
+// Map half-width marks to the full-width form stored in the glyph library.
+fn normalize_punctuation(c: char) -> char {
+    match c {
+        ',' => '，',
+        '.' => '。',
+        ';' => '；',
+        ':' => '：',
+        '?' => '？',
+        '!' => '！',
+        _ => c,
+    }
+}
+
+/// Split the content into layout tokens.
+pub fn tokenize(content: &str, mode: PunctuationMode) -> Vec<Token> {
+    content
+        .chars()
+        .filter(|&c| c != '\r')
+        .map(|c| match (is_punctuation(c), mode) {
+            _ if c == '\n' => Token::LineBreak,
+            _ if c == STANZA_BREAK => Token::StanzaBreak,
+            (false, _) => Token::Word(c),
+            (true, PunctuationMode::Render) => Token::Punctuation(normalize_punctuation(c)),
+            (true, PunctuationMode::Drop) => Token::Pause,
+        })
+        .collect()
+}
+
+/// The characters drawn for the content, in order, without break markers.
+///
+/// The n-th character matches the n-th entry of a word list.
+pub fn drawn_chars(content: &str, mode: PunctuationMode) -> Vec<char> {
+    tokenize(content, mode)
+        .iter()
+        .filter_map(Token::drawn_char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_marks_leave_a_pause() {
+        assert_eq!(
+            tokenize("春眠，曉", PunctuationMode::Drop),
+            [
+                Token::Word('春'),
+                Token::Word('眠'),
+                Token::Pause,
+                Token::Word('曉')
+            ]
+        );
+        assert_eq!(
+            drawn_chars("春眠，曉。", PunctuationMode::Drop),
+            ['春', '眠', '曉']
+        );
+    }
+
+    #[test]
+    fn rendered_marks_take_their_full_width_form() {
+        assert_eq!(
+            tokenize("曉,鳥?", PunctuationMode::Render),
+            [
+                Token::Word('曉'),
+                Token::Punctuation('，'),
+                Token::Word('鳥'),
+                Token::Punctuation('？')
+            ]
+        );
+    }
+
+    #[test]
+    fn breaks_end_the_line() {
+        let content = "春眠\n曉¶鳥";
+        assert_eq!(
+            tokenize(content, PunctuationMode::Drop),
+            [
+                Token::Word('春'),
+                Token::Word('眠'),
+                Token::LineBreak,
+                Token::Word('曉'),
+                Token::StanzaBreak,
+                Token::Word('鳥')
+            ]
+        );
+        assert_eq!(drawn_chars("春\r\n眠", PunctuationMode::Drop), ['春', '眠']);
+    }
+}
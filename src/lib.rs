@@ -5,4 +5,7 @@ pub mod api;
 pub mod feature;
 
 pub static DB: OnceLock<Database> = OnceLock::new();
+/// Database kept across restarts, holding the saved projects.
+pub static PROJECT_DB: OnceLock<Database> = OnceLock::new();
 pub const KEY: &str = "default_gen";
+pub const PROJECT_KEY: &str = "projects";